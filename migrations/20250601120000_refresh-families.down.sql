@@ -0,0 +1,6 @@
+-- Add migration script here
+DROP TABLE IF EXISTS security_events;
+
+DROP INDEX IF EXISTS refresh_tokens_family_id_idx;
+ALTER TABLE refresh_tokens DROP COLUMN IF EXISTS rotated_at;
+ALTER TABLE refresh_tokens DROP COLUMN IF EXISTS family_id;
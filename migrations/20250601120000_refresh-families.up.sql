@@ -0,0 +1,17 @@
+-- Add migration script here
+-- существующие токены получают каждый свою семью
+ALTER TABLE refresh_tokens ADD COLUMN family_id TEXT NOT NULL DEFAULT gen_random_uuid()::TEXT;
+ALTER TABLE refresh_tokens ALTER COLUMN family_id DROP DEFAULT;
+ALTER TABLE refresh_tokens ADD COLUMN rotated_at TIMESTAMPTZ;
+
+CREATE INDEX ON refresh_tokens (family_id);
+
+CREATE TABLE security_events (
+    id BIGSERIAL PRIMARY KEY,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL,
+    details TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX ON security_events (user_id);
@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE refresh_tokens ADD COLUMN user_agent TEXT;
+ALTER TABLE refresh_tokens ADD COLUMN ip TEXT;
+ALTER TABLE refresh_tokens ADD COLUMN last_used_at TIMESTAMPTZ;
+
+UPDATE refresh_tokens SET last_used_at = created_at;
+ALTER TABLE refresh_tokens ALTER COLUMN last_used_at SET NOT NULL;
+ALTER TABLE refresh_tokens ALTER COLUMN last_used_at SET DEFAULT now();
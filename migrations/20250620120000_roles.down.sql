@@ -0,0 +1,5 @@
+-- Add migration script here
+DROP TABLE user_roles;
+DROP TABLE role_permissions;
+DROP TABLE permissions;
+DROP TABLE roles;
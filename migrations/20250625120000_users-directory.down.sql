@@ -0,0 +1,4 @@
+-- Add migration script here
+DROP INDEX users_name_id_idx;
+DROP INDEX users_name_prefix_idx;
+DROP INDEX users_nickname_prefix_idx;
@@ -0,0 +1,6 @@
+-- Add migration script here
+-- поиск по префиксу: lower(col) LIKE 'prefix%'
+CREATE INDEX users_nickname_prefix_idx ON users (lower(nickname) text_pattern_ops);
+CREATE INDEX users_name_prefix_idx ON users (lower(name) text_pattern_ops);
+-- keyset пагинация при сортировке по name
+CREATE INDEX users_name_id_idx ON users (name, id);
@@ -0,0 +1,166 @@
+use axum::{
+    body::Body, response::Response,
+    extract::{DefaultBodyLimit, Request},
+    routing::{delete, get, patch, post},
+    Router
+};
+use tower::{limit::ConcurrencyLimitLayer,
+    service_fn, ServiceBuilder};
+use tower_http::{services::ServeFile, trace::TraceLayer, compression::CompressionLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer}, catch_panic::CatchPanicLayer};
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tracing::info_span;
+
+use crate::models::*;
+use crate::handlers::*;
+
+impl AppState {
+    /// Подключается к Postgres и Redis и загружает ключи JWT по настройкам
+    pub async fn connect(config: Config, clock: Arc<dyn Clock>) -> Result<AppState, StartupError> {
+        let pool = Arc::new(DataBase::create_connection(&config.database).await?);
+        let redis_pool = Arc::new(Redis::create_connection(&config.redis).await?);
+        let keys = Arc::new(KeyStore::load(&config.jwt, Arc::clone(&clock)).await?);
+        let breaker = Arc::new(CircuitBreaker::new(config.redis.failure_threshold));
+        let bus: Arc<dyn EventBus> = Arc::new(RedisEventBus::new(Arc::clone(&redis_pool), Arc::clone(&breaker), &config.redis));
+        let remote = BreakerCache::wrap(Arc::new(RedisCache::new(redis_pool)), Arc::clone(&breaker), Duration::from_secs(config.redis.probe_interval));
+        let cache = LayeredCache::wrap(remote, &*bus, &config.redis, Arc::clone(&clock));
+
+        let tokens: Arc<dyn RefreshTokenRepository> = Arc::new(PgRefreshTokenRepository::new(Arc::clone(&pool), Arc::clone(&clock)));
+        DataBase::spawn_token_purge(&tokens, Duration::from_secs(config.jwt.purge_interval));
+
+        Ok(AppState {
+            users: Arc::new(PgUserRepository::new(pool)),
+            tokens,
+            cache,
+            flights: Arc::new(SingleFlight::new()),
+            bus,
+            breaker,
+            hasher: Arc::new(HashPool::new(&config.argon)),
+            keys,
+            config: Arc::new(config),
+            clock,
+        })
+    }
+
+    /// Пользователи, токены и кеш в памяти процесса, без Postgres и Redis. Ключи JWT читаются как обычно
+    pub async fn in_memory(config: Config, clock: Arc<dyn Clock>) -> Result<AppState, StartupError> {
+        let keys = Arc::new(KeyStore::load(&config.jwt, Arc::clone(&clock)).await?);
+        let breaker = Arc::new(CircuitBreaker::new(config.redis.failure_threshold));
+        let bus: Arc<dyn EventBus> = Arc::new(MemoryEventBus::new());
+        let remote = BreakerCache::wrap(Arc::new(MemoryCache::new(Arc::clone(&clock))), Arc::clone(&breaker), Duration::from_secs(config.redis.probe_interval));
+        let cache = LayeredCache::wrap(remote, &*bus, &config.redis, Arc::clone(&clock));
+
+        let tokens: Arc<dyn RefreshTokenRepository> = Arc::new(MemoryRefreshTokenRepository::new(Arc::clone(&clock)));
+        DataBase::spawn_token_purge(&tokens, Duration::from_secs(config.jwt.purge_interval));
+
+        Ok(AppState {
+            users: Arc::new(MemoryUserRepository::new()),
+            tokens,
+            cache,
+            flights: Arc::new(SingleFlight::new()),
+            bus,
+            breaker,
+            hasher: Arc::new(HashPool::new(&config.argon)),
+            keys,
+            config: Arc::new(config),
+            clock,
+        })
+    }
+}
+
+/// Все маршруты и middleware приложения, без привязки к сокету: тесты вызывают его через `oneshot`
+pub fn build_router(state: AppState) -> Router {
+    let first_page = Router::new().route("/", get(main_page).post(main_page).delete(main_page).put(main_page));
+    let register_page = Router::new().route("/reg/{nickname}/{name}/{password}", get(register));
+    let api_register_page = Router::new().route("/api/v1/auth/register", post(api_register));
+    let profile_page = Router::new().route("/profile/{nickname}", get(profile));
+    let greet_page = Router::new().route("/{name}", get(greet).with_state(ExampleData {a: 3}));
+    let all_users_page = Router::new().route("/all", get(all_users));
+    let my_profile_page = Router::new().route("/profile", get(my_profile));
+    let login_page = Router::new().route("/login/{nickname}/{password}", get(login));
+    let api_login_page = Router::new().route("/api/v1/auth/login", post(api_login));
+    let api_refresh_page = Router::new().route("/api/v1/auth/refresh", post(api_refresh));
+    let jwks_page = Router::new().route("/.well-known/jwks.json", get(jwks));
+    let health_page = Router::new()
+                            .route("/health", get(health))
+                            .route("/metrics", get(metrics));
+    let cipher_text_path = Router::new().route("/cipher/{data}", get(cipher_text));
+    let update_user_path = Router::new().route("/api/v1/users/me", patch(update_me));
+    let sessions_page = Router::new()
+                            .route("/api/v1/sessions", get(sessions))
+                            .route("/api/v1/sessions/{jti}", delete(revoke_session))
+                            .route("/api/v1/sessions/revoke-all", post(revoke_all_sessions));
+    let admin_roles_page = Router::new()
+                            .route("/api/v1/admin/users/{id}/roles", post(grant_role))
+                            .route("/api/v1/admin/users/{id}/roles/{role}", delete(revoke_role))
+                            .route_layer(RequirePermission("roles:write"));
+    let admin_users_page = Router::new()
+                            .route("/api/v1/admin/users/{id}/roles", get(user_roles))
+                            .route("/api/v1/admin/password-hashes", get(password_hash_report))
+                            .route_layer(RequirePermission("users:read"));
+    let logout_page = Router::new().route("/logout", get(logout));
+
+    let files = Router::new()
+                            .route_service("/toml", ServeFile::new("Cargo.toml"))
+                            .route_service("/static", ServeFile::new("static/message.txt"));
+
+    let mut routes = Router::new()
+                .merge(my_profile_page)
+                .merge(profile_page)
+                .merge(update_user_path)
+                .merge(logout_page)
+                .merge(sessions_page)
+                .merge(admin_roles_page)
+                .merge(admin_users_page)
+                .layer(AuthLayer { state: state.clone() })
+                .merge(first_page)
+                .merge(greet_page)
+                .merge(all_users_page)
+                .merge(api_register_page)
+                .merge(api_login_page)
+                .merge(api_refresh_page)
+                .merge(cipher_text_path)
+                .merge(jwks_page)
+                .merge(health_page);
+
+    // старые GET маршруты с паролем в URL, отключаются через server.legacy_auth_routes = false
+    if state.config.server.legacy_auth_routes {
+        routes = routes
+                .merge(register_page)
+                .merge(login_page);
+    }
+
+    Router::new()
+                .without_v07_checks()
+                .merge(routes)
+                .merge(files)
+                //.nest("/", foobar) не используется
+
+                .route_service("/i", service_fn(|req: Request| async move {
+                    let body = Body::from(format!("/i page, method: {}", req.method()));
+                    let res = Response::new(body);
+                    Ok::<_, Infallible>(res)
+
+                }))
+                //.route_layer(CompressionLayer::new()) не знаю что делает
+                //.fallback_service(service) не знаю что делает
+                .fallback(fallback)
+                .method_not_allowed_fallback(method_fallback)
+                // внутри ErrorLayer, чтобы ответ на панику тоже получил request id
+                .layer(CatchPanicLayer::custom(AppError::from_panic))
+
+                .layer(
+                    ServiceBuilder::new()
+                        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+                        .layer(PropagateRequestIdLayer::x_request_id())
+                        .layer(DefaultBodyLimit::max(4096))
+                        .layer(TraceLayer::new_for_http().make_span_with(|_req: &Request<_>| {
+                            info_span!("request: ", method = %_req.method(), uri = %_req.uri(), versions = ?_req.version())}))
+                        .layer(CompressionLayer::new())
+                        .layer(ErrorLayer)
+                        .layer(ConcurrencyLimitLayer::new(250))
+                        //.layer(BufferLayer::new(500))
+                        //.layer(TimeoutLayer::new(Duration::from_secs(15)))
+                    )
+                .with_state(state)
+}
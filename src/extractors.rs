@@ -0,0 +1,91 @@
+use axum::{extract::{ConnectInfo, FromRequest, FromRequestParts, Request},
+    http::{header, request::Parts, Extensions, HeaderMap, StatusCode},
+    Form, Json};
+use serde::de::DeserializeOwned;
+use std::{convert::Infallible, net::SocketAddr};
+
+use crate::models::{AppError, AuthUser, Claims, DeviceInfo, JsonOrForm, OptionalAuthUser};
+
+
+impl<S, T> FromRequest<S> for JsonOrForm<T>
+where
+    S: Send + Sync,
+    T: DeserializeOwned + Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let content_type = req.headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|val| val.to_str().ok())
+            .unwrap_or("")
+            .to_owned();
+
+        if content_type.starts_with("application/json") {
+            match Json::<T>::from_request(req, state).await {
+                Ok(Json(data)) => return Ok(JsonOrForm(data)),
+                Err(e) => return Err(AppError::InvalidBody { status: e.status(), message: e.body_text() }),
+            }
+        }
+
+        if content_type.starts_with("application/x-www-form-urlencoded") {
+            match Form::<T>::from_request(req, state).await {
+                Ok(Form(data)) => return Ok(JsonOrForm(data)),
+                Err(e) => return Err(AppError::InvalidBody { status: e.status(), message: e.body_text() }),
+            }
+        }
+
+        Err(AppError::InvalidBody {
+            status: StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            message: "Expected `application/json` or `application/x-www-form-urlencoded` body".to_owned(),
+        })
+    }
+}
+
+impl DeviceInfo {
+    pub fn from_headers(headers: &HeaderMap, extensions: &Extensions) -> DeviceInfo {
+        let user_agent = headers.get(header::USER_AGENT)
+            .and_then(|val| val.to_str().ok())
+            .map(|val| val.chars().take(512).collect());
+        let ip = extensions.get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip().to_string());
+
+        DeviceInfo { user_agent, ip }
+    }
+}
+
+impl<S> FromRequestParts<S> for DeviceInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(DeviceInfo::from_headers(&parts.headers, &parts.extensions))
+    }
+}
+
+impl<S> FromRequestParts<S> for AuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        match parts.extensions.get::<Claims>() {
+            Some(claims) => Ok(AuthUser(claims.clone())),
+            None => Err(AppError::Unauthorized),
+        }
+    }
+}
+
+impl<S> FromRequestParts<S> for OptionalAuthUser
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(OptionalAuthUser(parts.extensions.get::<Claims>().cloned()))
+    }
+}
@@ -1,15 +1,15 @@
-use axum::{extract::{rejection::JsonRejection, ConnectInfo, Extension, Json, Path, Request, State}, 
+use axum::{extract::{rejection::JsonRejection, ConnectInfo, Extension, Json, Path, Query, Request, State}, 
     http::{header, HeaderMap, StatusCode, Uri}, 
     response::{Html, IntoResponse, Redirect, Response}};
 use cookie::Cookie;
-use deadpool_redis::Pool;
 use http::HeaderValue;
-use log::{info, error};
+use log::{info, error, warn};
 use std::{net::SocketAddr, sync::Arc};
 use crate::models::*;
+use crate::services::database_service::{USERS_BY_ID, USERS_BY_NICKNAME};
 use serde_json::Value;
 use axum_extra::extract::{cookie::SameSite, CookieJar};
-use sqlx::PgPool;
+use uuid::Uuid;
 
 
 
@@ -32,7 +32,7 @@ pub async fn method_fallback() -> &'static str {
 async fn _all_the_things(uri: Uri, _payload: Result<Json<Value>, JsonRejection>) -> impl IntoResponse {
     let mut header_map = HeaderMap::new();
     if uri.path() == "/" {
-        header_map.insert(header::SERVER, "axum".parse().unwrap());
+        header_map.insert(header::SERVER, HeaderValue::from_static("axum"));
     }
 
 
@@ -51,52 +51,7 @@ async fn _all_the_things(uri: Uri, _payload: Result<Json<Value>, JsonRejection>)
     )
 }
 
-pub async fn register(Path(data): Path<RegisterForm>, State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>) -> impl IntoResponse {
-    let mut transaction: sqlx::Transaction<'static, sqlx::Postgres> = pool.begin().await.expect("Ошибка создания transaction из pool");
-    let user = match DataBase::save_user(&data.nickname, &data.name, &data.password, &mut transaction, Arc::clone(&redis_pool)).await {
-        Ok(user) => user,
-        Err(_) => {
-            transaction.rollback().await.expect("Не удалось rollback transaction 1");
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    };
-    
-    let access_token = match Jwt::create_acc_token(&format!("{}", &user.id), "User").await {
-        Ok(token) => token,
-        Err(_) => {
-            transaction.rollback().await.expect("Не удалось rollback transaction 2");
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    };
-    //info!("Созданный access token: {access_token}");
-
-    let refresh_token = match Jwt::create_ref_token(&format!("{}", &user.id), "User").await {
-        Ok(token) => token,
-        Err(_) => {
-            transaction.rollback().await.expect("Не удалось rollback transaction 3");
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    };
-    transaction.commit().await.expect("Не удалось commit transaction");
-
-    match DataBase::save_ref_token(&refresh_token, Arc::clone(&pool)).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Ошибка сохранения ref token в БД: {e}");
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    }
-    
-    Redis::redis_del(Arc::clone(&redis_pool), "user:all").await.expect("Не удалось удалить all в redis");
-    
+fn set_auth_cookies(response: &mut Response, access_token: String, refresh_token: String) {
     let mut access_cookie = Cookie::new("AccessToken", access_token);
     access_cookie.set_http_only(false);
     access_cookie.set_secure(false);
@@ -109,188 +64,214 @@ pub async fn register(Path(data): Path<RegisterForm>, State((pool, redis_pool)):
     refresh_cookie.set_path("/");
     refresh_cookie.set_same_site(SameSite::Strict);
 
-    
-    let mut response: Response = Redirect::to("/profile")
-        .into_response();
-    *response.status_mut() = StatusCode::SEE_OTHER;
     response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&access_cookie.to_string())
         .unwrap_or(HeaderValue::from_static("")));
     response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&refresh_cookie.to_string())
         .unwrap_or(HeaderValue::from_static("")));
+}
+
+/// Ответ после входа: данные пользователя и cookie, либо токены в теле при `?mode=token`
+fn auth_response(status: StatusCode, user: OwnProfile, access_token: String, refresh_token: String, mode: TokenMode, expires_in: i64) -> Response {
+    if mode == TokenMode::Token {
+        let body = TokenResponse { access_token, refresh_token, token_type: "Bearer", expires_in };
+        return (status, Json(body)).into_response()
+    }
 
+    let mut response = (status, Json(user)).into_response();
+    set_auth_cookies(&mut response, access_token, refresh_token);
     response
+}
 
+async fn register_user(data: &RegisterForm, device: &DeviceInfo, state: AppState) -> Result<(OwnProfile, String, String), AppError> {
+    let AppState { users, tokens, cache, hasher, bus, keys, config, .. } = state;
+    data.validate().map_err(AppError::Validation)?;
+
+    let user = DataBase::save_user(&data.nickname, data.name.trim(), &data.password, Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await?;
+
+    // пользователь без токенов занимал бы nickname, поэтому при ошибке дальше он удаляется
+    let issued: Result<(String, String), AppError> = async {
+        // у нового пользователя есть только BASE_ROLE
+        let grants = users.get_user_grants(&format!("{}", &user.id)).await?;
+        let access_token = Jwt::create_acc_token(&format!("{}", &user.id), &grants, Arc::clone(&keys)).await?;
+        let refresh_token = Jwt::create_ref_token(&format!("{}", &user.id), grants.primary_role(), Arc::clone(&keys)).await?;
+        DataBase::save_ref_token(&refresh_token, &Uuid::new_v4().to_string(), device, Arc::clone(&keys), tokens, hasher).await?;
+        Ok((access_token, refresh_token))
+    }.await;
+    let (access_token, refresh_token) = match issued {
+        Ok(pair) => pair,
+        Err(e) => {
+            DataBase::discard_user(&user, users, cache).await;
+            return Err(e)
+        }
+    };
 
+    DataBase::invalidate_users_pages(cache).await;
+    bus.notify(CacheEvent::UserChanged { id: user.id, old_nickname: None, new_nickname: user.nickname.clone() }).await;
+
+    Ok((OwnProfile::from(&user), access_token, refresh_token))
 }
 
-pub async fn profile(Path(nickname): Path<String>, State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>, extension: Option<Extension<Claims>>) -> impl IntoResponse {
-    let user = match DataBase::get_user(&nickname, Arc::clone(&pool), Arc::clone(&redis_pool)).await {
-        Ok(user) => user,
-        Err(_) => return (StatusCode::NOT_FOUND, Html("<h1>User not found</h1>".to_string()))
-    };
-    let mut _body = String::new();
-
-    if let Some(claims) = extension {
-        if claims.sub == format!("{}", user.id) {
-            _body = format!(
-                "
-                <h1>Your profile</h1>\n\
-                <p><strong>Nickname:</strong> {}</p>\n\
-                <p><strong>Name:</strong> {}</p>\n\
-                <p><strong>ID:</strong> {}</p>\n\
-                <p><strong>Password:</strong> {}</p>\n\
-                ",
-                user.nickname,
-                user.name,
-                user.id,
-                user.password,
-            );
-        } else {
-            _body = format!(
-                "<h1>Profile</h1>\n\
-                <p><strong>Nickname:</strong> {}</p>\n\
-                <p><strong>Name:</strong> {}</p>",
-                user.nickname,
-                user.name,
-            );
-        }
+pub async fn register(Path(data): Path<RegisterForm>, State(state): State<AppState>, device: DeviceInfo) -> Result<Response, AppError> {
+    let (_, access_token, refresh_token) = register_user(&data, &device, state).await?;
+
+    let mut response: Response = Redirect::to("/profile")
+        .into_response();
+    *response.status_mut() = StatusCode::SEE_OTHER;
+    set_auth_cookies(&mut response, access_token, refresh_token);
+
+    Ok(response)
+}
+
+pub async fn api_register(State(state): State<AppState>, Query(query): Query<AuthQuery>, device: DeviceInfo, JsonOrForm(data): JsonOrForm<RegisterForm>) -> Result<Response, AppError> {
+    let expires_in = state.config.jwt.access_ttl;
+    let (user, access_token, refresh_token) = register_user(&data, &device, state).await?;
+
+    Ok(auth_response(StatusCode::CREATED, user, access_token, refresh_token, query.mode, expires_in))
+}
+
+pub async fn profile(Path(nickname): Path<String>, State(AppState { users, cache, flights, config, .. }): State<AppState>, OptionalAuthUser(claims): OptionalAuthUser) -> Result<impl IntoResponse, AppError> {
+    let user = DataBase::get_user(&nickname, users, cache, flights, &config).await?;
+
+    let body = if claims.is_some_and(|claims| claims.sub == format!("{}", user.id)) {
+        format!(
+            "
+            <h1>Your profile</h1>\n\
+            <p><strong>Nickname:</strong> {}</p>\n\
+            <p><strong>Name:</strong> {}</p>\n\
+            <p><strong>ID:</strong> {}</p>\n\
+            ",
+            user.nickname,
+            user.name,
+            user.id,
+        )
     } else {
-        _body = format!(
+        format!(
             "<h1>Profile</h1>\n\
             <p><strong>Nickname:</strong> {}</p>\n\
             <p><strong>Name:</strong> {}</p>",
             user.nickname,
             user.name,
-        );
-    }
-
+        )
+    };
 
-    (StatusCode::FOUND, Html(_body))
-        
+    Ok((StatusCode::FOUND, Html(body)))
 }
 
-pub async fn all_users(State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>) -> impl IntoResponse {
-    let users = DataBase::get_all_users(Arc::clone(&pool), Arc::clone(&redis_pool)).await;
-    match users {
-        Ok(users) => {
-            return (StatusCode::FOUND, Json(users))
-        }
-        Err(_) => return (StatusCode::NOT_FOUND, Json(vec![]))
-    }
-}
+pub async fn all_users(State(AppState { users, cache, flights, config, .. }): State<AppState>, Query(list): Query<UserListQuery>) -> Result<Json<UserPage>, AppError> {
+    list.validate().map_err(AppError::Validation)?;
 
-pub async fn my_profile(State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>, extensions: Option<Extension<Claims>>) -> impl IntoResponse {
-    let claims = match extensions {
-        Some(claims) => claims,
-        None => {
-            let mut res = Html("<h1>You're not authorized</h1>").into_response();
-            *res.status_mut() = StatusCode::UNAUTHORIZED;
-            return res
-        }
-    };
+    let page = DataBase::get_users_page(&list, users, cache, flights, &config).await?;
+    Ok(Json(page))
+}
 
-    let user = DataBase::get_user_by_id(&claims.sub, Arc::clone(&pool), Arc::clone(&redis_pool)).await;
-    match user {
-        Ok(user) => {
-            let mut res = Redirect::to(&format!("/profile/{}", &user.nickname)).into_response();
-            *res.status_mut() = StatusCode::SEE_OTHER;
-            return res
-        },
-        Err(_) => {
-            let mut res = Html("<h1>Error, try again later</h1>").into_response();
-            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return res
-        }
-    }
+pub async fn my_profile(State(AppState { users, cache, flights, config, .. }): State<AppState>, AuthUser(claims): AuthUser) -> Result<Response, AppError> {
+    let user = DataBase::get_user_by_id(&claims.sub, users, cache, flights, &config).await?;
 
+    let mut res = Redirect::to(&format!("/profile/{}", &user.nickname)).into_response();
+    *res.status_mut() = StatusCode::SEE_OTHER;
+    Ok(res)
 }
 
-pub async fn login(Path(data): Path<(String, String)>, State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>) -> impl IntoResponse {
-    let nickname = data.0;
-    let passwordd = data.1;
-    //println!("{}", &passwordd);
+async fn login_user(data: &LoginForm, device: &DeviceInfo, state: AppState) -> Result<(OwnProfile, String, String), AppError> {
+    let AppState { users, tokens, hasher, keys, config, .. } = state;
+    data.validate().map_err(AppError::Validation)?;
 
-    //Redis::redis_del(Arc::clone(&redis_pool), &format!("user_nick:{}", &nickname)).await.expect("Не удалось удалить пользователя из Redis");
-    let user_data = DataBase::get_user(&nickname, Arc::clone(&pool), Arc::clone(&redis_pool)).await;
-    let user = match user_data {
+    // не сообщаем, существует ли nickname
+    let user = match users.get_user_credentials(&data.nickname).await {
         Ok(user) => user,
-        Err(_) => {
-            let mut resp: Response = Html("<h1>User not found</h1>").into_response();
-            *resp.status_mut() = StatusCode::NOT_FOUND;
-            return resp
-        }
+        Err(DataBaseError::NotFound) => return Err(AppError::InvalidCredentials),
+        Err(e) => return Err(e.into())
     };
 
-    match Argon::verify_hash(&user.password, &passwordd).await {
-        Ok(_) => (),
-        Err(_) => {
-            let mut resp: Response = Html("<h1>Incorrect password, try again</h1>").into_response();
-            *resp.status_mut() = StatusCode::BAD_REQUEST;
-            return resp
-        }
+    match Argon::verify_hash(user.password.as_str(), &data.password, &hasher).await {
+        Ok(()) => (),
+        Err(e @ ArgonError::Busy { .. }) => return Err(e.into()),
+        Err(_) => return Err(AppError::InvalidCredentials),
     }
-
-    let acc_token = match Jwt::create_acc_token(&format!("{}", &user.id), "User").await {
-        Ok(token) => token,
-        Err(_) => {
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    };
-
-    let refresh_token = match Jwt::create_ref_token(&format!("{}", &user.id), "User").await {
-        Ok(token) => token,
-        Err(_) => {
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
-    };
-
-    match DataBase::save_ref_token(&refresh_token, Arc::clone(&pool)).await {
-        Ok(_) => (),
-        Err(e) => {
-            error!("Ошибка сохранения ref token в БД: {e}");
-            let mut resp: Response = Html("<h1>Error, try again later</h1>").into_response();
-            *resp.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return resp
-        }
+    if Argon::needs_rehash(user.password.as_str(), &config.argon) {
+        DataBase::rehash_password(&user, &data.password, Arc::clone(&users), Arc::clone(&hasher), &config).await;
     }
 
-    let mut acc_cookie = Cookie::new("AccessToken", acc_token);
-    acc_cookie.set_http_only(false);
-    acc_cookie.set_secure(false);
-    acc_cookie.set_path("/");
-    acc_cookie.set_same_site(SameSite::Strict);
+    let grants = users.get_user_grants(&format!("{}", &user.id)).await?;
+    let acc_token = Jwt::create_acc_token(&format!("{}", &user.id), &grants, Arc::clone(&keys)).await?;
+    let refresh_token = Jwt::create_ref_token(&format!("{}", &user.id), grants.primary_role(), Arc::clone(&keys)).await?;
+    DataBase::save_ref_token(&refresh_token, &Uuid::new_v4().to_string(), device, Arc::clone(&keys), tokens, hasher).await?;
 
-    let mut ref_cookie = Cookie::new("RefreshToken", refresh_token);
-    ref_cookie.set_http_only(false);
-    ref_cookie.set_secure(false);
-    ref_cookie.set_path("/");
-    ref_cookie.set_same_site(SameSite::Strict);
+    Ok((OwnProfile::from(&user), acc_token, refresh_token))
+}
 
+pub async fn login(Path((nickname, password)): Path<(String, String)>, State(state): State<AppState>, device: DeviceInfo) -> Result<Response, AppError> {
+    let data = LoginForm { nickname, password };
+    let (_, acc_token, refresh_token) = login_user(&data, &device, state).await?;
 
     let mut response: Response = Redirect::to("/profile").into_response();
     *response.status_mut() = StatusCode::SEE_OTHER;
-    response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&acc_cookie.to_string())
-        .unwrap_or(HeaderValue::from_static("")));
-    response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&ref_cookie.to_string())
-        .unwrap_or(HeaderValue::from_static("")));
+    set_auth_cookies(&mut response, acc_token, refresh_token);
 
-    response
+    Ok(response)
+}
+
+pub async fn api_login(State(state): State<AppState>, Query(query): Query<AuthQuery>, device: DeviceInfo, JsonOrForm(data): JsonOrForm<LoginForm>) -> Result<Response, AppError> {
+    let expires_in = state.config.jwt.access_ttl;
+    let (user, acc_token, refresh_token) = login_user(&data, &device, state).await?;
+
+    Ok(auth_response(StatusCode::OK, user, acc_token, refresh_token, query.mode, expires_in))
+}
+
+/// Обмен refresh токена из тела запроса на новую пару, для клиентов без cookie
+pub async fn api_refresh(State(state): State<AppState>, device: DeviceInfo, JsonOrForm(data): JsonOrForm<RefreshForm>) -> Result<Json<TokenResponse>, AppError> {
+    let pair = Jwt::refresh_pair(&data.refresh_token, &device, &state).await
+        .map_err(|e| {
+            info!("Не удалось обновить токены: {e}");
+            AppError::InvalidRefreshToken(e)
+        })?;
+
+    Ok(Json(TokenResponse {
+        access_token: pair.access_token,
+        refresh_token: pair.refresh_token,
+        token_type: "Bearer",
+        expires_in: state.config.jwt.access_ttl,
+    }))
 }
 
 
-pub async fn cipher_text(Path(text): Path<String>) -> impl IntoResponse {
-    let data = match Aes::encrypt_data(&text).await {
+pub async fn jwks(State(AppState { keys, .. }): State<AppState>) -> impl IntoResponse {
+    let mut res = Json(keys.current().jwks(&*keys.clock)).into_response();
+    res.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("public, max-age=300"));
+    res
+}
+
+/// 200, пока отвечает БД. Недоступный Redis переводит сервис в `degraded`, но не выключает его
+pub async fn health(State(AppState { users, breaker, .. }): State<AppState>) -> (StatusCode, Json<HealthReport>) {
+    let database_up = users.ping().await.is_ok();
+    let cache = if breaker.is_open() { "bypassed" } else { "up" };
+    let (status, code) = match (database_up, breaker.is_open()) {
+        (false, _) => ("down", StatusCode::SERVICE_UNAVAILABLE),
+        (true, true) => ("degraded", StatusCode::OK),
+        (true, false) => ("ok", StatusCode::OK),
+    };
+
+    let report = HealthReport {
+        status: status.to_owned(),
+        database: if database_up { "up" } else { "down" }.to_owned(),
+        cache: cache.to_owned(),
+    };
+    (code, Json(report))
+}
+
+pub async fn metrics(State(AppState { breaker, hasher, .. }): State<AppState>) -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], breaker.metrics() + &hasher.metrics())
+}
+
+pub async fn cipher_text(Path(text): Path<String>, State(AppState { config, .. }): State<AppState>) -> impl IntoResponse {
+    let data = match Aes::encrypt_data(&text, &config.aes).await {
         Ok(res) => res,
         Err(e) => {
             return format!("Не удалось зашифровать данные: {e}")
         }
     };
 
-    let decrypted = match Aes::decrypt_data(&data).await {
+    let decrypted = match Aes::decrypt_data(&data, &config.aes).await {
         Ok(decrypted) => decrypted,
         Err(e) => format!("Не удалось расшифровать данные: {e}")
     };
@@ -298,85 +279,156 @@ pub async fn cipher_text(Path(text): Path<String>) -> impl IntoResponse {
     format!("Изначальные данные: {text}\n\nЗашифровано: {data:?}\nРасшифровано: {decrypted:?}")
 }
 
-pub async fn update_user(State((pool, redis_pool)): State<(Arc<PgPool>, Arc<Pool>)>, Path(data): Path<(String, String)>, extension: Option<Extension<Claims>>) -> impl IntoResponse {
-    let claims = match extension {
-        Some(claims) => claims,
-        None => {
-            let mut res = Html("<h1>You're not logged in</h1>").into_response();
-            *res.status_mut() = StatusCode::UNAUTHORIZED;
-            return res
-        }  
-    };
+pub async fn update_me(State(AppState { users, tokens, cache, flights, hasher, bus, keys, config, .. }): State<AppState>, AuthUser(claims): AuthUser, JsonOrForm(patch): JsonOrForm<UserPatch>) -> Result<Json<OwnProfile>, AppError> {
+    patch.validate().map_err(AppError::Validation)?;
+
+    // старый nickname нужен, чтобы сбросить его кеш
+    let old_user = DataBase::get_user_by_id(&claims.sub, Arc::clone(&users), Arc::clone(&cache), flights, &config).await?;
 
-    let field = data.0;
-    let change_to = data.1;
-    let mut transaction = pool.begin().await.expect("Не удалось начать транзакцию"); 
+    let user = DataBase::update_user(&claims.sub, &patch, Arc::clone(&users), hasher, &config).await?;
 
-    match DataBase::update_user(&claims.sub, &change_to, &field, &mut transaction).await {
-        Ok(_) => transaction.commit().await.expect("Не удалось commit транзакцию"),
-        Err(_) => {
-            let mut res = Html("<h1>Error, try again later</h1>").into_response();
-            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return res
+    // после смены пароля все выданные токены перестают действовать
+    if patch.password.is_some() {
+        if let Err(e) = tokens.del_all_ref_tokens(&claims.sub).await {
+            error!("Не удалось удалить refresh токены после смены пароля: {e}");
         }
+        if let Err(e) = Jwt::revoke_user_tokens(&claims.sub, Arc::clone(&keys), Arc::clone(&cache)).await {
+            error!("Не удалось отозвать access токены после смены пароля: {e}");
+        }
+        bus.notify(CacheEvent::TokensRevoked { user_id: claims.sub.clone() }).await;
     }
 
-    Redis::redis_del(Arc::clone(&redis_pool), &format!("user:{}", &claims.sub)).await.expect("Не удалось удалить из Redis пользователя по id");
-    Redis::redis_del(Arc::clone(&redis_pool), "user:all").await.expect("Не удалось удалить из Redis пользователя по nickname");
-
-    let user = match DataBase::get_user_by_id(&claims.sub, Arc::clone(&pool), Arc::clone(&redis_pool)).await {
-        Ok(user) => user,
-        Err(_) => {
-            let mut res = Html("<h1>Error, try again later</h1>").into_response();
-            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-            return res
+    for key in [USERS_BY_ID.key(user.id), USERS_BY_NICKNAME.key(&old_user.nickname), USERS_BY_NICKNAME.key(&user.nickname)] {
+        if let Err(e) = cache.del(&key).await {
+            error!("Не удалось удалить {key} из Redis: {e}");
         }
-    };
+    }
+    DataBase::invalidate_users_pages(Arc::clone(&cache)).await;
+    bus.notify(CacheEvent::UserChanged { id: user.id, old_nickname: Some(old_user.nickname), new_nickname: user.nickname.clone() }).await;
+
+    Ok(Json(OwnProfile::from(&user)))
+}
 
-    Redis::redis_del(Arc::clone(&redis_pool), &format!("user_nick:{}", &user.nickname)).await.expect("Не удалось удалить из Redis пользователя по nickname");
+fn clear_auth_cookies(response: &mut Response) {
+    // путь и SameSite совпадают с set_auth_cookies, иначе браузер не заменит выданные cookie
+    let mut access_cookie = Cookie::new("AccessToken", "");
+    access_cookie.set_path("/");
+    access_cookie.set_same_site(SameSite::Strict);
+    access_cookie.set_max_age(cookie::time::Duration::seconds(-1));
 
-    let mut res = Redirect::to(&format!("/profile/{}", user.nickname)).into_response();
-    *res.status_mut() = StatusCode::SEE_OTHER;
-    res
+    let mut refresh_cookie = Cookie::new("RefreshToken", "");
+    refresh_cookie.set_path("/");
+    refresh_cookie.set_same_site(SameSite::Strict);
+    refresh_cookie.set_max_age(cookie::time::Duration::seconds(-1));
+
+    response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&access_cookie.to_string())
+        .unwrap_or(HeaderValue::from_static("")));
+    response.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&refresh_cookie.to_string())
+        .unwrap_or(HeaderValue::from_static("")));
 }
 
-pub async fn logout(extensions: Option<Extension<Claims>>, State(pool): State<Arc<PgPool>>, req: Request) -> impl IntoResponse {
-    if let Some(_) = extensions {
+pub async fn logout(OptionalAuthUser(claims): OptionalAuthUser, State(AppState { tokens, cache, hasher, keys, .. }): State<AppState>, req: Request) -> Result<Response, AppError> {
+    if let Some(claims) = claims {
+        if let Err(e) = Jwt::revoke_acc_token(&claims, Arc::clone(&keys), Arc::clone(&cache)).await {
+            error!("Не удалось отозвать access token: {e}");
+        }
+
         let jar = CookieJar::from_headers(req.headers());
         let refresh_token = Jwt::get_refresh_token(&jar).await;
 
-        let refresh_token_claims = match Jwt::verify_ref_token(&refresh_token, Arc::clone(&pool), false).await {
-            Ok(token) => token,
-            Err(_) => {
-                let mut res = Html("<h1>Error, try again later</h1>").into_response();
-                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return res
-            }
-        };
-
-        match DataBase::del_ref_token(&refresh_token_claims.sub, &refresh_token_claims.jti, Arc::clone(&pool)).await {
-            Ok(_) => (),
-            Err(_) => {
-                let mut res = Html("<h1>Error, try again later</h1>").into_response();
-                *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                return res
-            }
+        // без refresh токена или с истёкшим выход всё равно выполняется: cookie очищаются ниже
+        match Jwt::verify_ref_token(&refresh_token, Arc::clone(&keys), Arc::clone(&tokens), hasher, false).await {
+            Ok(refresh_claims) if refresh_claims.sub == claims.sub => match tokens.del_ref_token(&refresh_claims.sub, &refresh_claims.jti).await {
+                Ok(()) => info!("refresh token удален"),
+                Err(e) => error!("Не удалось удалить refresh токен при выходе: {e}"),
+            },
+            Ok(_) => warn!("Refresh токен при выходе принадлежит другому пользователю, не удаляется"),
+            Err(e) => info!("Refresh токен при выходе не удалён: {e}"),
         }
-        info!("refresh token удален");
     }
 
-    let mut access_cookie = Cookie::new("AccessToken", "");
-    access_cookie.set_max_age(cookie::time::Duration::seconds(-1));
+    let mut res = Html("<h1>You successfully logout</h1>").into_response();
+    clear_auth_cookies(&mut res);
+    Ok(res)
+}
 
-    let mut refresh_cookie = Cookie::new("RefreshToken", "");
-    refresh_cookie.set_max_age(cookie::time::Duration::seconds(-1));
+pub async fn sessions(State(AppState { tokens, hasher, keys, .. }): State<AppState>, AuthUser(claims): AuthUser, jar: CookieJar) -> Result<Json<Vec<SessionView>>, AppError> {
+    let sessions = tokens.get_sessions(&claims.sub).await?;
 
-    let mut res = Html("<h1>You successfully logout</h1>").into_response();
-    *res.status_mut() = StatusCode::OK;
-    res.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&access_cookie.to_string())
-        .unwrap_or(HeaderValue::from_static("")));
-    res.headers_mut().append(http::header::SET_COOKIE, HeaderValue::from_str(&refresh_cookie.to_string())
-        .unwrap_or(HeaderValue::from_static("")));
-    res
+    let refresh_token = Jwt::get_refresh_token(&jar).await;
+    let current_jti = match Jwt::verify_ref_token(&refresh_token, Arc::clone(&keys), Arc::clone(&tokens), hasher, false).await {
+        Ok(refresh_claims) => refresh_claims.jti,
+        Err(_) => String::new(),
+    };
+
+    let sessions: Vec<SessionView> = sessions.into_iter()
+        .map(|session| SessionView { current: session.jti == current_jti, session })
+        .collect();
+
+    Ok(Json(sessions))
+}
+
+/// Удаляет refresh токены сессии. Access токены не привязаны к сессии, поэтому уже выданный
+/// access токен этой сессии действует до конца `jwt.access_ttl`; отозвать сразу все - revoke-all
+pub async fn revoke_session(State(AppState { tokens, .. }): State<AppState>, Path(jti): Path<String>, AuthUser(claims): AuthUser) -> Result<StatusCode, AppError> {
+    let sessions = tokens.get_sessions(&claims.sub).await?;
+    if !sessions.iter().any(|session| session.jti == jti) {
+        return Err(AppError::SessionNotFound)
+    }
+
+    tokens.del_ref_token(&claims.sub, &jti).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-}
\ No newline at end of file
+pub async fn revoke_all_sessions(State(AppState { tokens, cache, bus, keys, .. }): State<AppState>, AuthUser(claims): AuthUser) -> Result<Response, AppError> {
+    tokens.del_all_ref_tokens(&claims.sub).await?;
+    if let Err(e) = Jwt::revoke_user_tokens(&claims.sub, Arc::clone(&keys), Arc::clone(&cache)).await {
+        error!("Не удалось отозвать access токены пользователя: {e}");
+    }
+    bus.notify(CacheEvent::TokensRevoked { user_id: claims.sub.clone() }).await;
+    info!("Все сессии пользователя {} удалены", claims.sub);
+
+    let mut res = StatusCode::NO_CONTENT.into_response();
+    clear_auth_cookies(&mut res);
+    Ok(res)
+}
+
+/// После изменения ролей выданные access токены отзываются, новые получат актуальные роли при refresh
+async fn roles_changed(admin: &Claims, user_id: &str, kind: &str, role: &str, AppState { users, cache, bus, keys, .. }: AppState) {
+    if let Err(e) = Jwt::revoke_user_tokens(user_id, keys, cache).await {
+        error!("Не удалось отозвать access токены после изменения ролей: {e}");
+    }
+    bus.notify(CacheEvent::TokensRevoked { user_id: user_id.to_owned() }).await;
+    let details = serde_json::json!({"role": role, "by": admin.sub}).to_string();
+    // роль уже изменена, поэтому запрос не проваливается, но пропуск в журнале аудита должен быть виден
+    if let Err(e) = users.save_security_event(user_id, kind, &details).await {
+        error!("Не удалось записать событие {kind} ({details}) для {user_id}: {e}");
+    }
+    info!("Пользователь {} {kind} {role} для {user_id}", admin.sub);
+}
+
+pub async fn user_roles(State(AppState { users, .. }): State<AppState>, Path(user_id): Path<i64>) -> Result<Json<UserGrants>, AppError> {
+    let grants = users.get_user_grants(&user_id.to_string()).await?;
+    Ok(Json(grants))
+}
+
+pub async fn password_hash_report(State(AppState { users, config, .. }): State<AppState>) -> Result<Json<PasswordHashReport>, AppError> {
+    let report = DataBase::password_hash_report(users, &config).await?;
+    Ok(Json(report))
+}
+
+pub async fn grant_role(State(state): State<AppState>, Path(user_id): Path<i64>, AuthUser(admin): AuthUser, JsonOrForm(data): JsonOrForm<RoleForm>) -> Result<StatusCode, AppError> {
+    let user_id = user_id.to_string();
+    state.users.grant_role(&user_id, &data.role).await?;
+    roles_changed(&admin, &user_id, "role_granted", &data.role, state).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn revoke_role(State(state): State<AppState>, Path((user_id, role)): Path<(i64, String)>, AuthUser(admin): AuthUser) -> Result<StatusCode, AppError> {
+    let user_id = user_id.to_string();
+    state.users.revoke_role(&user_id, &role).await?;
+    roles_changed(&admin, &user_id, "role_revoked", &role, state).await;
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -1,4 +1,8 @@
+#![allow(clippy::needless_return)]
+
 pub mod services;
 pub mod handlers;
 pub mod models;
 pub mod middlewares;
+pub mod extractors;
+pub mod app;
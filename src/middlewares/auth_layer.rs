@@ -7,15 +7,14 @@ use std::sync::Arc;
 use axum_extra::extract::cookie::CookieJar;
 use cookie::{Cookie, SameSite};
 use log::info;
-use sqlx::PgPool;
 
-use crate::models::{AuthLayer, AuthLayerService, Claims, DataBase, Jwt};
+use crate::models::{AuthLayer, AuthLayerService, DeviceInfo, Jwt};
 
 
 impl<S> Layer<S> for AuthLayer {
     type Service = AuthLayerService<S>;
     fn layer(&self, inner: S) -> Self::Service {
-        AuthLayerService {inner: Some(inner), db_conn: Arc::clone(&self.db_conn)}
+        AuthLayerService {inner: Some(inner), state: self.state.clone()}
     }
 }
 
@@ -39,29 +38,42 @@ where
 
     fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
         let jar = CookieJar::from_headers(req.headers());
+        let bearer_token = Jwt::get_bearer_token(req.headers());
+        let device = DeviceInfo::from_headers(req.headers(), req.extensions());
         let mut future = self.inner.take().expect("Service called after completion");
-        let connection_database = Arc::clone(&self.db_conn);
+        let state = self.state.clone();
+        let cache = Arc::clone(&self.state.cache);
+        let keys = Arc::clone(&self.state.keys);
 
         Box::pin(async move {
             info!("AuthLayer работает!");
             let mut n_access_token = String::new();
             let mut n_refresh_token = String::new();
-            let pool = connection_database;
-            let access_token = Jwt::get_access_token(&jar).await;
-            
-
-            if let Ok(claims) = Jwt::verify_acc_token(&access_token).await {
+            // Bearer токен важнее cookie, и для него нет автоматического обновления:
+            // клиент сам вызывает /api/v1/auth/refresh
+            let use_bearer = !bearer_token.is_empty();
+            let access_token = if use_bearer { bearer_token } else { Jwt::get_access_token(&jar).await };
+
+            let access_claims = match Jwt::verify_acc_token(&access_token, Arc::clone(&keys)).await {
+                Ok(claims) if Jwt::is_acc_token_revoked(&claims, Arc::clone(&keys), Arc::clone(&cache)).await => {
+                    info!("Access токен {} отозван", claims.jti);
+                    None
+                },
+                Ok(claims) => Some(claims),
+                Err(_) => None,
+            };
+
+            if let Some(claims) = access_claims {
                 req.extensions_mut().insert(claims);
-                
-            } else {
+            } else if !use_bearer {
                 let refresh_token = Jwt::get_refresh_token(&jar).await;
 
-                if let Ok((claims, refresh_token)) = all_checks(Arc::clone(&pool), true, &refresh_token).await {
-                    n_refresh_token = refresh_token;    
-                    let access_token = Jwt::create_acc_token(&claims.sub, &claims.role).await.unwrap_or("".to_owned());
+                // при переполненном пуле хеширования запрос просто остаётся неавторизованным
+                if let Ok(pair) = Jwt::refresh_pair(&refresh_token, &device, &state).await {
+                    n_refresh_token = pair.refresh_token;
 
-                    if let Ok(access_claims) = Jwt::verify_acc_token(&access_token).await {
-                        n_access_token = access_token;
+                    if let Ok(access_claims) = Jwt::verify_acc_token(&pair.access_token, Arc::clone(&keys)).await {
+                        n_access_token = pair.access_token;
                         req.extensions_mut().insert(access_claims);
                     }
                 }
@@ -97,26 +109,3 @@ where
         })
     }
 }
-
-async fn all_checks(pool: Arc<PgPool>, search_in_db: bool, refresh_token: &str) -> Result<(Claims, String), String> {
-    if let Ok(claims) = Jwt::verify_ref_token(&refresh_token, Arc::clone(&pool), search_in_db).await {
-        if let Ok(()) = DataBase::del_ref_token(&claims.sub, &claims.jti, Arc::clone(&pool)).await {
-
-            let refresh_token = Jwt::create_ref_token(&claims.sub, &claims.role).await.unwrap_or("".to_owned());
-            DataBase::save_ref_token(&refresh_token, Arc::clone(&pool)).await.unwrap_or(());
-            
-            match Jwt::verify_ref_token(&refresh_token, Arc::clone(&pool), true).await {
-                Ok(claims) => Ok((claims, refresh_token)),
-                Err(e) => Err(format!("Error checking refresh token: {e}"))
-            }
-            
-        } else {
-            Err("Error deleting refresh token".to_owned())
-        }
-    } else {
-        Err("Error checking refresh troken".to_owned())
-    }
-}
-
-
-
@@ -0,0 +1,97 @@
+use axum::{body::Body, response::Response, extract::Request, http::{header, HeaderValue}};
+use futures_util::future::BoxFuture;
+use tower::{Service, Layer};
+use std::task::{Context, Poll};
+use log::error;
+
+use crate::models::{ApiError, ErrorLayer, ErrorLayerService};
+
+
+impl<S> Layer<S> for ErrorLayer {
+    type Service = ErrorLayerService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        ErrorLayerService { inner }
+    }
+}
+
+/// Браузер получает HTML, остальные клиенты (Accept: */*, application/json) - JSON
+fn wants_html(accept: &str) -> bool {
+    let html = accept.find("text/html");
+    let json = accept.find("application/json");
+    match (html, json) {
+        (Some(html), Some(json)) => html < json,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;").replace('\'', "&#39;")
+}
+
+fn render_html(body: &ApiError) -> String {
+    let fields = body.fields.iter()
+        .map(|e| format!("<li>{}: {}</li>", e.field, escape_html(&e.message)))
+        .collect::<String>();
+    let mut html = format!("<h1>{}</h1>\n", escape_html(&body.message));
+    if !fields.is_empty() {
+        html.push_str(&format!("<ul>{fields}</ul>\n"));
+    }
+    if let Some(request_id) = &body.request_id {
+        html.push_str(&format!("<p>Request id: {}</p>\n", escape_html(request_id)));
+    }
+    html
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for ErrorLayerService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let html = req.headers().get(header::ACCEPT)
+            .and_then(|val| val.to_str().ok())
+            .is_some_and(wants_html);
+        // x-request-id ставит SetRequestIdLayer, он должен стоять снаружи этого layer'а
+        let request_id = req.headers().get("x-request-id")
+            .and_then(|val| val.to_str().ok())
+            .map(str::to_owned);
+        let method = req.method().clone();
+        let uri = req.uri().clone();
+
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let mut response = inner.call(req).await?;
+            let Some(mut body) = response.extensions_mut().remove::<ApiError>() else {
+                return Ok(response)
+            };
+            body.request_id = request_id;
+
+            if response.status().is_server_error() {
+                error!("{method} {uri} -> {} {}, request id: {}", response.status(), body.error, body.request_id.as_deref().unwrap_or("-"));
+            }
+
+            let (mut parts, _) = response.into_parts();
+            let (content_type, data) = if html {
+                ("text/html; charset=utf-8", render_html(&body))
+            } else {
+                ("application/json", serde_json::to_string(&body).unwrap_or_default())
+            };
+            parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            parts.headers.remove(header::CONTENT_LENGTH);
+
+            Ok(Response::from_parts(parts, Body::from(data)))
+        })
+    }
+}
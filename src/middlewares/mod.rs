@@ -1 +1,3 @@
-pub mod auth_layer;
\ No newline at end of file
+pub mod auth_layer;
+pub mod role_layer;
+pub mod error_layer;
@@ -0,0 +1,69 @@
+use axum::{response::{IntoResponse, Response}, extract::Request};
+use futures_util::future::BoxFuture;
+use tower::{Service, Layer};
+use std::task::{Context, Poll};
+use log::info;
+
+use crate::models::{AccessRule, AppError, Claims, RequireAccessService, RequirePermission, RequireRole};
+
+
+impl<S> Layer<S> for RequireRole {
+    type Service = RequireAccessService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireAccessService { inner, rule: AccessRule::Role(self.0) }
+    }
+}
+
+impl<S> Layer<S> for RequirePermission {
+    type Service = RequireAccessService<S>;
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireAccessService { inner, rule: AccessRule::Permission(self.0) }
+    }
+}
+
+impl AccessRule {
+    fn allows(&self, claims: &Claims) -> bool {
+        match self {
+            AccessRule::Role(role) => claims.has_role(role),
+            AccessRule::Permission(permission) => claims.has_permission(permission),
+        }
+    }
+}
+
+impl<S, ReqBody> Service<Request<ReqBody>> for RequireAccessService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    ReqBody: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // claims кладёт AuthLayer, поэтому этот layer должен стоять внутри него
+        let allowed = match req.extensions().get::<Claims>() {
+            Some(claims) if self.rule.allows(claims) => Ok(()),
+            Some(claims) => {
+                info!("Пользователю {} запрещён доступ, нужно {:?}", claims.sub, self.rule);
+                Err(AppError::Forbidden.into_response())
+            },
+            None => Err(AppError::Unauthorized.into_response()),
+        };
+
+        // сервис, прошедший poll_ready, забираем себе, на его место ставим клон
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            match allowed {
+                Ok(()) => inner.call(req).await,
+                Err(res) => Ok(res),
+            }
+        })
+    }
+}
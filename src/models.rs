@@ -1,7 +1,14 @@
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use std::{any::Any, collections::HashMap, net::SocketAddr, sync::{atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize}, Arc, Mutex, RwLock}, time::SystemTime};
+use async_trait::async_trait;
+use futures::future::{BoxFuture, Shared};
+use chrono::{DateTime, Utc};
+use jsonwebtoken::{DecodingKey, EncodingKey};
+use deadpool_redis::Pool;
+use tokio::sync::{broadcast, Semaphore};
 use sqlx::{prelude::FromRow, PgPool};
 use thiserror::Error;
+use axum::http::StatusCode;
 
 #[derive(Debug, Clone, Copy)]
 pub struct ExampleData {
@@ -19,22 +26,370 @@ pub struct Claims {
     pub aud: String,
     pub exp: usize,
     pub iat: usize,
+    /// Время выдачи в миллисекундах, сравнивается с watermark отзыва. В старых токенах его нет
+    #[serde(default)]
+    pub iat_ms: i64,
     pub jti: String,
+    /// Основная роль, оставлена для старых токенов и клиентов
+    pub role: String,
+    /// Все роли пользователя, читаются из БД при выдаче access токена
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Права всех ролей пользователя
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub scopes: Vec<String>,
+}
+
+/// Роли и права пользователя из таблиц `user_roles`/`role_permissions`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserGrants {
+    pub roles: Vec<String>,
+    pub scopes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoleForm {
     pub role: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct Jwt;
 
+/// Описание одного ключа в `keyring.json`
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyRingEntry {
+    pub kid: String,
+    pub private: Option<String>,
+    pub public: String,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct KeyRingFile {
+    pub active: String,
+    #[serde(default = "default_grace_days")]
+    pub grace_days: i64,
+    pub keys: Vec<KeyRingEntry>,
+}
+
+fn default_grace_days() -> i64 {
+    30
+}
+
+#[derive(Clone)]
+pub struct SigningKey {
+    pub kid: String,
+    pub encoding: Option<EncodingKey>,
+    pub decoding: DecodingKey,
+    /// Сырой публичный ключ Ed25519 (32 байта) для JWKS
+    pub public_raw: Vec<u8>,
+    pub retired_at: Option<DateTime<Utc>>,
+}
+
+/// Набор ключей подписи: один активный и ключи, выведенные из оборота, которые
+/// ещё принимаются при проверке в течение `grace`
+#[derive(Clone)]
+pub struct KeyRing {
+    pub active: String,
+    pub grace: chrono::Duration,
+    pub keys: Vec<SigningKey>,
+    /// Файлы, из которых собран набор (manifest и PEM), для отслеживания изменений
+    pub sources: Vec<String>,
+}
+
+/// Разобранные ключи, загруженные один раз при старте и подменяемые при перезагрузке
+pub struct KeyStore {
+    pub ring: RwLock<Arc<KeyRing>>,
+    pub modified: Mutex<Vec<(String, Option<SystemTime>)>>,
+    /// Путь к keyring и время жизни токенов
+    pub settings: JwtConfig,
+    /// Ключ digest'ов refresh токенов, разбирается один раз при загрузке
+    pub refresh_key: RefreshTokenKey,
+    /// Время выдачи токенов и окна ротации
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Ключ HMAC-SHA256 для digest'ов refresh токенов из `jwt.refresh_token_key`
+#[derive(Clone)]
+pub struct RefreshTokenKey(pub(crate) [u8; 32]);
+
+/// Общее состояние приложения, передаётся во все маршруты через `State<AppState>`
+#[derive(Clone)]
+pub struct AppState {
+    pub users: Arc<dyn UserRepository>,
+    pub tokens: Arc<dyn RefreshTokenRepository>,
+    pub cache: Arc<dyn Cache>,
+    pub flights: Arc<SingleFlight>,
+    pub bus: Arc<dyn EventBus>,
+    pub breaker: Arc<CircuitBreaker>,
+    pub hasher: Arc<HashPool>,
+    pub keys: Arc<KeyStore>,
+    pub config: Arc<Config>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Источник текущего времени, в тестах подменяется
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock;
+
+/// Пользователи, их роли и security events. Кеширование и хеширование паролей выше, в `DataBase`
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Занятый nickname возвращается как `Conflict("nickname")`
+    async fn create_user(&self, nickname: &str, name: &str, password_hash: &str) -> Result<User, DataBaseError>;
+    /// Удаляет пользователя вместе с его ролями и refresh токенами
+    async fn delete_user(&self, id: i64) -> Result<(), DataBaseError>;
+    /// Пользователь вместе с хешем пароля, для входа
+    async fn get_user_credentials(&self, nickname: &str) -> Result<User, DataBaseError>;
+    async fn get_user(&self, nickname: &str) -> Result<OwnProfile, DataBaseError>;
+    async fn get_user_by_id(&self, id: &str) -> Result<OwnProfile, DataBaseError>;
+    /// Не больше `limit` пользователей после курсора `list.after` с фильтром по префиксу `list.q`
+    async fn find_users(&self, list: &UserListQuery, limit: i64) -> Result<Vec<OwnProfile>, DataBaseError>;
+    /// `password_hash` уже посчитан из `patch.password`
+    async fn update_user(&self, id: &str, patch: &UserPatch, password_hash: Option<&str>) -> Result<User, DataBaseError>;
+    async fn get_user_grants(&self, user_id: &str) -> Result<UserGrants, DataBaseError>;
+    async fn grant_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError>;
+    async fn revoke_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError>;
+    async fn save_security_event(&self, user_id: &str, kind: &str, details: &str) -> Result<(), DataBaseError>;
+    /// Проверка соединения для `/health`
+    async fn ping(&self) -> Result<(), DataBaseError>;
+    /// Заменяет хеш пароля, только если он не менялся с момента проверки.
+    /// `false` - пароль успели сменить, новый хеш не записан
+    async fn replace_password_hash(&self, user_id: i64, old_hash: &PasswordHash, new_hash: &str) -> Result<bool, DataBaseError>;
+    /// Пользователи, сгруппированные по алгоритму, параметрам и длине хеша пароля
+    async fn password_hash_groups(&self) -> Result<Vec<PasswordHashGroup>, DataBaseError>;
+}
+
+/// Refresh токены и сессии (семьи токенов)
+#[async_trait]
+pub trait RefreshTokenRepository: Send + Sync {
+    async fn save_ref_token(&self, token: &RefreshTokenRecord) -> Result<(), DataBaseError>;
+    /// Хеш сохранённого токена, сравнение с самим токеном делает вызывающий
+    async fn get_token_hash(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError>;
+    /// Владелец и jti токена с данным digest'ом, `NotFound` если такого нет
+    async fn find_token_by_digest(&self, digest: &str) -> Result<(i64, String), DataBaseError>;
+    /// Помечает токен как использованный. Если он уже был ротирован, возвращает `TokenReuse` с его семьёй
+    async fn rotate_ref_token(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError>;
+    async fn revoke_ref_family(&self, user_id: &str, family_id: &str) -> Result<(), DataBaseError>;
+    /// Удаляет сессию целиком: токен и всю его семью
+    async fn del_ref_token(&self, user_id: &str, jti: &str) -> Result<(), DataBaseError>;
+    async fn del_all_ref_tokens(&self, user_id: &str) -> Result<(), DataBaseError>;
+    /// Не ротированные и не истёкшие токены, новые сессии первыми
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<Session>, DataBaseError>;
+    /// Удаляет истёкшие токены, в том числе ротированные, и возвращает их число
+    async fn purge_expired_tokens(&self) -> Result<u64, DataBaseError>;
+}
+
+/// Строковый key-value кеш с TTL в секундах. Отсутствующий ключ в `get_str` это `NoneError`.
+/// Типизированные `get`/`set`/`mget`/`mset` поверх него реализованы для `dyn Cache`
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError>;
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError>;
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError>;
+    /// Все пары с одним TTL
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError>;
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError>;
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError>;
+}
+
+/// Изменения, после которых копии данных в кеше других экземпляров устаревают
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum CacheEvent {
+    /// Пользователь создан или изменён, `old_nickname` - nickname до переименования
+    UserChanged { id: i64, old_nickname: Option<String>, new_nickname: String },
+    /// Обновился watermark `TOKENS_VALID_AFTER` пользователя
+    TokensRevoked { user_id: String },
+}
+
+/// Доставка `CacheEvent` всем экземплярам приложения, включая отправителя
+#[async_trait]
+pub trait EventBus: Send + Sync {
+    async fn publish(&self, event: &CacheEvent) -> Result<(), CustomRedisError>;
+    /// События, опубликованные после подписки
+    fn subscribe(&self) -> broadcast::Receiver<CacheEvent>;
+}
+
+/// Redis pub/sub: канал `CACHE_EVENTS_CHANNEL`, фоновая задача пересылает сообщения подписчикам
+pub struct RedisEventBus {
+    pub(crate) pool: Arc<Pool>,
+    /// Пока Redis обходится, события не публикуются
+    pub(crate) breaker: Arc<CircuitBreaker>,
+    pub(crate) events: broadcast::Sender<CacheEvent>,
+}
+
+/// События внутри одного процесса
+pub struct MemoryEventBus {
+    pub(crate) events: broadcast::Sender<CacheEvent>,
+}
+
+/// L1 кеш в памяти процесса перед общим кешем. Хранит только найденные значения не дольше `ttl`,
+/// устаревшие копии удаляются по `CacheEvent`
+pub struct LayeredCache {
+    pub(crate) local: MemoryCache,
+    pub(crate) remote: Arc<dyn Cache>,
+    pub(crate) ttl: u64,
+    pub(crate) capacity: usize,
+}
+
+/// Circuit breaker общего кеша: после `threshold` ошибок подряд кеш обходится,
+/// пока фоновая проверка не увидит, что он снова отвечает
+#[derive(Debug, Default)]
+pub struct CircuitBreaker {
+    pub(crate) threshold: u32,
+    pub(crate) open: AtomicBool,
+    /// Ошибки подряд с последнего успешного запроса
+    pub(crate) failures: AtomicU32,
+    pub(crate) failures_total: AtomicU64,
+    pub(crate) bypassed_total: AtomicU64,
+    pub(crate) opened_total: AtomicU64,
+}
+
+/// Границы ожидания в секундах для гистограммы `rp_hash_queue_wait_seconds`
+pub const HASH_WAIT_BUCKETS: [f64; 8] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// Пул для Argon2: не больше `workers` хешей одновременно и не больше `queue` ждущих,
+/// чтобы всплеск входов не занял всю память
+#[derive(Debug)]
+pub struct HashPool {
+    pub(crate) workers: Arc<Semaphore>,
+    pub(crate) queue: usize,
+    pub(crate) retry_after: u64,
+    pub(crate) waiting: AtomicUsize,
+    pub(crate) rejected_total: AtomicU64,
+    /// Накопительные счётчики по `HASH_WAIT_BUCKETS`
+    pub(crate) wait_buckets: [AtomicU64; HASH_WAIT_BUCKETS.len()],
+    pub(crate) wait_count: AtomicU64,
+    pub(crate) wait_micros: AtomicU64,
+}
+
+/// Кеш за `CircuitBreaker`: при открытом breaker запросы сразу возвращают `Unavailable`
+pub struct BreakerCache {
+    pub(crate) inner: Arc<dyn Cache>,
+    pub(crate) breaker: Arc<CircuitBreaker>,
+}
+
+/// Ответ `/health`: 503 только если недоступна БД, без Redis сервис работает в режиме `degraded`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: String,
+    pub database: String,
+    pub cache: String,
+}
+
 #[derive(Debug, Clone)]
-pub struct AuthLayer {
-    pub db_conn: Arc<PgPool>,
+pub struct PgUserRepository {
+    pub pool: Arc<PgPool>,
+}
+
+/// Время ротации и истечения считается по `clock`, как и окно grace в `Jwt::refresh_pair`, а не по часам БД
+#[derive(Clone)]
+pub struct PgRefreshTokenRepository {
+    pub pool: Arc<PgPool>,
+    pub clock: Arc<dyn Clock>,
+}
+
+/// Пространство ключей кеша `<name>:v<version>:<id>`. Версия увеличивается при изменении
+/// формата значений, старые ключи тогда просто истекают
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheNamespace {
+    pub name: &'static str,
+    pub version: u32,
+}
+
+pub(crate) type Flight = Shared<BoxFuture<'static, Result<Arc<dyn Any + Send + Sync>, DataBaseError>>>;
+
+/// Одновременные заполнения кеша по одному ключу: в хранилище идёт только первый запрос,
+/// остальные получают его результат
+#[derive(Default)]
+pub struct SingleFlight {
+    pub(crate) calls: Mutex<HashMap<String, Flight>>,
+}
+
+#[derive(Clone)]
+pub struct RedisCache {
+    pub pool: Arc<Pool>,
+}
+
+/// Хранилища в памяти процесса, чтобы проверять весь auth flow без Postgres и Redis
+pub struct MemoryUserRepository {
+    pub(crate) users: Mutex<Vec<User>>,
+    /// Выданные роли по id пользователя, BASE_ROLE не хранится
+    pub(crate) user_roles: Mutex<HashMap<i64, Vec<String>>>,
+    /// Права каждой роли, как в миграции roles
+    pub(crate) role_permissions: HashMap<String, Vec<String>>,
+    pub(crate) events: Mutex<Vec<SecurityEvent>>,
+}
+
+pub struct MemoryRefreshTokenRepository {
+    pub(crate) tokens: Mutex<Vec<RefreshTokenRecord>>,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+pub struct MemoryCache {
+    /// Значение и момент, после которого оно считается удалённым
+    pub(crate) entries: Mutex<HashMap<String, (String, DateTime<Utc>)>>,
+    pub(crate) clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug, Clone)]
+pub struct SecurityEvent {
+    pub user_id: String,
+    pub kind: String,
+    pub details: String,
+}
+
+#[derive(Clone)]
+pub struct AuthLayer {
+    pub state: AppState,
+}
+
+#[derive(Clone)]
 pub struct AuthLayerService<S> {
     pub inner: Option<S>,
-    pub db_conn: Arc<PgPool>
+    pub state: AppState,
+}
+
+/// Claims авторизованного пользователя, без них запрос отклоняется с 401
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub Claims);
+
+/// Claims, если пользователь авторизован
+#[derive(Debug, Clone)]
+pub struct OptionalAuthUser(pub Option<Claims>);
+
+/// Правило доступа к маршруту, проверяется по Claims после AuthLayer
+#[derive(Debug, Clone, Copy)]
+pub enum AccessRule {
+    Role(&'static str),
+    Permission(&'static str),
+}
+
+/// Layer: пропускает только пользователей с ролью, например `RequireRole("Admin")`
+#[derive(Debug, Clone, Copy)]
+pub struct RequireRole(pub &'static str);
+
+/// Layer: пропускает только пользователей с правом, например `RequirePermission("roles:write")`
+#[derive(Debug, Clone, Copy)]
+pub struct RequirePermission(pub &'static str);
+
+#[derive(Clone)]
+pub struct RequireAccessService<S> {
+    pub inner: S,
+    pub rule: AccessRule,
+}
+
+/// Пара токенов, выданная при ротации, хранится в Redis несколько секунд,
+/// чтобы параллельные запросы со старым refresh токеном получили ту же пару
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RotatedPair {
+    pub access_token: String,
+    pub refresh_token: String,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -45,6 +400,52 @@ pub struct HashExtractDb {
     pub token_hash: String,
 }
 
+/// Данные об устройстве, сохраняемые вместе с refresh токеном
+#[derive(Debug, Clone, Default)]
+pub struct DeviceInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+/// Сессия — семья refresh токенов, представленная текущим (не ротированным) токеном
+#[derive(Debug, Clone, FromRow, Serialize)]
+pub struct Session {
+    pub jti: String,
+    pub started_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionView {
+    #[serde(flatten)]
+    pub session: Session,
+    pub current: bool,
+}
+
+/// Строка таблицы refresh_tokens, `token_hash` - HMAC digest самого токена
+/// (у токенов, выданных до перехода на HMAC, - Argon2 хеш)
+#[derive(Debug, Clone)]
+pub struct RefreshTokenRecord {
+    pub jti: String,
+    pub user_id: i64,
+    pub token_hash: String,
+    pub family_id: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: DateTime<Utc>,
+    pub rotated_at: Option<DateTime<Utc>>,
+    pub device: DeviceInfo,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub struct RotatedTokenDb {
+    pub family_id: String,
+    pub rotated_at: Option<DateTime<Utc>>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Argon;
 
@@ -60,6 +461,8 @@ pub enum ArgonError {
     VerifyHashError,
     #[error("Tokio runtime error")]
     TokioError,
+    #[error("Hashing queue is full")]
+    Busy { retry_after: u64 },
 }
 
 #[derive(Debug, Error)]
@@ -70,6 +473,22 @@ pub enum JwtError {
     ReadingKey(#[from] std::io::Error),
     #[error("Error: not found in database")]
     DataBaseNotFound,
+    #[error("Key ring error: {0}")]
+    KeyRing(String),
+    #[error("Unknown or expired key id: {0}")]
+    UnknownKid(String),
+    #[error("Token revocation error: {0}")]
+    Revocation(String),
+    #[error("Refresh error: {0}")]
+    Refresh(String),
+    #[error("Refresh token reuse detected, family revoked")]
+    RefreshReuse,
+    #[error("Hashing is overloaded, retry after {0}s")]
+    Overloaded(u64),
+    #[error("Wrong token type: {0:?}")]
+    WrongType(Option<String>),
+    #[error("Invalid refresh token key: {0}")]
+    RefreshKey(String),
 }
 
 #[derive(Debug, Clone)]
@@ -91,6 +510,165 @@ pub struct RegisterForm {
     pub password: String,
 }
 
+/// Частичное изменение пользователя, `None` оставляет поле как есть.
+/// Новые поля добавляются сюда и в `DataBase::update_user`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UserPatch {
+    pub nickname: Option<String>,
+    pub name: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoginForm {
+    pub nickname: String,
+    pub password: String,
+}
+
+/// Тело запроса, принятое как `application/json` или `application/x-www-form-urlencoded`
+#[derive(Debug, Clone)]
+pub struct JsonOrForm<T>(pub T);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiError {
+    pub error: &'static str,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+/// Ошибка обработки запроса. Статус и код выбираются в `error_service.rs`,
+/// формат ответа (JSON или HTML по Accept) и request id добавляет `ErrorLayer`
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error("Some fields are invalid")]
+    Validation(Vec<FieldError>),
+    #[error("Invalid body: {message}")]
+    InvalidBody { status: StatusCode, message: String },
+    #[error("Not authorized")]
+    Unauthorized,
+    #[error("Access denied")]
+    Forbidden,
+    #[error("Incorrect nickname or password")]
+    InvalidCredentials,
+    #[error("Invalid refresh token: {0}")]
+    InvalidRefreshToken(JwtError),
+    #[error("Session not found")]
+    SessionNotFound,
+    #[error(transparent)]
+    DataBase(#[from] DataBaseError),
+    #[error(transparent)]
+    Sqlx(#[from] sqlx::Error),
+    #[error(transparent)]
+    Jwt(#[from] JwtError),
+    #[error(transparent)]
+    Argon(#[from] ArgonError),
+    #[error(transparent)]
+    Redis(#[from] CustomRedisError),
+    #[error(transparent)]
+    Aes(#[from] AesError),
+    #[error("Request handler panicked: {0}")]
+    Panic(String),
+}
+
+/// Layer: дописывает request id в ошибки `AppError` и отдаёт их в HTML, если клиент просит text/html
+#[derive(Debug, Clone, Copy)]
+pub struct ErrorLayer;
+
+#[derive(Clone)]
+pub struct ErrorLayerService<S> {
+    pub inner: S,
+}
+
+/// Профиль без секретов: отдаётся владельцу и только он хранится в кеше Redis
+#[derive(Debug, Clone, PartialEq, Eq, FromRow, Deserialize, Serialize)]
+pub struct OwnProfile {
+    pub id: i64,
+    pub nickname: String,
+    pub name: String,
+}
+
+/// Профиль, который видят остальные пользователи
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PublicProfile {
+    pub nickname: String,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserSort {
+    #[default]
+    Id,
+    Nickname,
+    Name,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// Параметры `/all`: `?after=<id>&limit=&q=&sort=id|nickname|name&order=asc|desc`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct UserListQuery {
+    pub after: Option<i64>,
+    pub limit: Option<i64>,
+    /// Префикс nickname или name, без учёта регистра
+    pub q: Option<String>,
+    #[serde(default)]
+    pub sort: UserSort,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+/// Страница `/all`, `next_after` передаётся в `?after=` для следующей страницы
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UserPage {
+    pub items: Vec<PublicProfile>,
+    pub next_after: Option<i64>,
+}
+
+/// Как отдавать токены после входа: в cookie (браузер) или в теле ответа (мобильное приложение, CLI)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenMode {
+    #[default]
+    Cookie,
+    Token,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuthQuery {
+    #[serde(default)]
+    pub mode: TokenMode,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefreshForm {
+    pub refresh_token: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: &'static str,
+    pub expires_in: i64,
+}
+
 #[derive(Debug, Clone, Error, Serialize)]
 pub enum DataBaseError {
     #[error("Argon error: {0}")]
@@ -103,18 +681,64 @@ pub enum DataBaseError {
     SomeTimeError(#[from] TimeCustomError),
     #[error("Non-valid token access/refresh")]
     NonValidToken,
+    #[error("Refresh token reused, family {family_id}")]
+    TokenReuse { family_id: String, rotated_at: Option<DateTime<Utc>> },
+    #[error("Value of {0} is already taken")]
+    Conflict(&'static str),
+    #[error("Role {0} not found")]
+    UnknownRole(String),
     #[error("Some sqlx error")]
     SqlxError,
     #[error("Some error")]
     SomeError,
 }
 
-#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+/// Строка таблицы users. Нет Serialize: наружу и в кеш уходят только `OwnProfile`/`PublicProfile`
+#[derive(Debug, Clone, FromRow)]
 pub struct User {
     pub id: i64,
     pub nickname: String,
     pub name: String,
-    pub password: String,
+    pub password: PasswordHash,
+}
+
+/// Argon2 хеш пароля, не сериализуется и не печатается в логах
+#[derive(Clone, PartialEq, Eq, sqlx::Type)]
+#[sqlx(transparent)]
+pub struct PasswordHash(pub(crate) String);
+
+/// Группа пользователей с одинаковыми параметрами хеша, `sample` - один из хешей группы для разбора
+#[derive(Debug, Clone, FromRow)]
+pub struct PasswordHashGroup {
+    pub sample: PasswordHash,
+    pub users: i64,
+}
+
+/// Алгоритм и параметры из строки хеша `$argon2id$v=19$m=..,t=..,p=..$salt$hash`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HashParams {
+    pub algorithm: String,
+    pub version: u32,
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub output_len: usize,
+}
+
+/// Сколько пользователей с параметрами хеша ниже `argon` из настроек, по группам параметров.
+/// `params` нет у хешей, которые не удалось разобрать
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PasswordParamsCount {
+    pub params: Option<HashParams>,
+    pub users: i64,
+    pub legacy: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PasswordHashReport {
+    pub total: i64,
+    pub legacy: i64,
+    pub groups: Vec<PasswordParamsCount>,
 }
 
 pub struct Redis;
@@ -129,6 +753,10 @@ pub enum CustomRedisError {
     NoneError,
     #[error("Error deleting")]
     DeleteError,
+    #[error("Cache value error: {0}")]
+    ValueError(String),
+    #[error("Cache is bypassed while the circuit is open")]
+    Unavailable,
 }
 
 pub struct Aes;
@@ -142,5 +770,125 @@ pub enum AesError {
     #[error("Encrypting file error")]
     EncryptFileError,
     #[error("Decrypting file error")]
-    DecryptFileError
+    DecryptFileError,
+    #[error("AES key is missing or invalid")]
+    KeyError,
+    #[error("Encrypted data is too short")]
+    InvalidData,
+    #[error("Read file error")]
+    ReadFileError,
+}
+
+/// Настройки сервера: `config.toml` (путь через APP_CONFIG) и переменные окружения поверх него
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub jwt: JwtConfig,
+    pub argon: ArgonConfig,
+    pub aes: AesConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: SocketAddr,
+    /// Старые GET маршруты с паролем в URL
+    pub legacy_auth_routes: bool,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    pub url: String,
+    /// Время жизни кеша пользователя в секундах
+    pub user_ttl: u64,
+    /// Время жизни закешированной страницы `/all` в секундах
+    pub page_ttl: u64,
+    /// Сколько секунд помнить, что пользователя нет
+    pub negative_ttl: u64,
+    /// Случайная добавка к TTL в процентах, чтобы ключи не истекали одновременно
+    pub ttl_jitter: u64,
+    /// Время жизни копий в L1 кеше процесса в секундах, 0 - L1 выключен
+    pub l1_ttl: u64,
+    /// Сколько ключей держать в L1 кеше
+    pub l1_capacity: usize,
+    /// Сколько ошибок Redis подряд открывают circuit breaker
+    pub failure_threshold: u32,
+    /// Как часто проверять Redis при открытом breaker, в секундах
+    pub probe_interval: u64,
+    /// Ожидание соединения из пула и его создания в миллисекундах
+    pub timeout_ms: u64,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JwtConfig {
+    pub keyring: String,
+    /// Время жизни access токена в секундах
+    pub access_ttl: i64,
+    /// Время жизни refresh токена в днях
+    pub refresh_ttl_days: i64,
+    /// Сколько секунд старый refresh токен отдаёт уже выданную при ротации пару
+    pub refresh_grace: u64,
+    /// Как часто в секундах из БД удаляются истёкшие refresh токены
+    pub purge_interval: u64,
+    /// Ключ HMAC-SHA256 для хранимых digest'ов refresh токенов, 32 байта в hex
+    pub refresh_token_key: String,
+    /// Принимать access токены, если denylist и watermark в Redis не удалось проверить.
+    /// По умолчанию такие токены отклоняются
+    pub revocation_fail_open: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArgonConfig {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+    pub output_len: usize,
+    /// Сколько хешей считается одновременно, каждый занимает `memory_kib` памяти
+    pub workers: usize,
+    /// Сколько запросов может ждать свободного worker'а, остальные сразу получают 503
+    pub queue: usize,
+    /// Значение `Retry-After` в секундах для отклонённых запросов
+    pub retry_after: u64,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AesConfig {
+    /// 32 байта в hex
+    pub key: String,
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Cannot read config file {path}: {source}")]
+    Read { path: String, source: std::io::Error },
+    #[error("Invalid config file {path}: {source}")]
+    Parse { path: String, source: toml::de::Error },
+    #[error("Invalid environment variable {name}: {message}")]
+    Env { name: &'static str, message: String },
+    #[error("Invalid config: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+/// Ошибки сборки `AppState` при старте
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("Cannot connect to database: {0}")]
+    Database(#[from] sqlx::Error),
+    #[error("Cannot load JWT keys: {0}")]
+    Jwt(#[from] JwtError),
+    #[error("Cannot create redis pool: {0}")]
+    Redis(#[from] CustomRedisError),
 }
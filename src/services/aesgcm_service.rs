@@ -1,12 +1,13 @@
 use aes_gcm::{aead::{generic_array::GenericArray, Aead, AeadCore, KeyInit}, Aes256Gcm, Key};
-use dotenv::dotenv;
-use std::env;
 use tokio::fs;
 use rand::{RngCore, rng};
 use hex::{/*encode*/ decode};
 use log::error;
 use std::path::Path;
-use crate::models::{Aes, AesError};
+use crate::models::{Aes, AesConfig, AesError};
+
+const NONCE_LEN: usize = 12;
+type Nonce = GenericArray<u8, <Aes256Gcm as AeadCore>::NonceSize>;
 
 impl Aes {
     pub async fn create_key() -> Key<Aes256Gcm> {
@@ -23,19 +24,37 @@ impl Aes {
         fs::write(path, hex_str).expect("Не удалось записать в файл");
     }*/
 
-    async fn create_nonce() -> GenericArray<u8, <Aes256Gcm as AeadCore>::NonceSize> {
-        let mut key = [0u8; 12];
+    /// `aes.key`: 32 байта в hex, проверяется при загрузке настроек
+    fn load_key(config: &AesConfig) -> Result<Key<Aes256Gcm>, AesError> {
+        let key_array: [u8; 32] = match decode(config.key.trim()).ok().and_then(|key| key.try_into().ok()) {
+            Some(key) => key,
+            None => {
+                error!("aes.key должен быть 32 байтами в формате hex");
+                return Err(AesError::KeyError)
+            }
+        };
+        Ok(Key::<Aes256Gcm>::clone_from_slice(&key_array))
+    }
+
+    /// Делит данные на nonce (первые 12 байт) и шифротекст
+    fn split_nonce(data: &[u8]) -> Result<(&Nonce, &[u8]), AesError> {
+        if data.len() < NONCE_LEN {
+            error!("Зашифрованные данные короче nonce: {} байт", data.len());
+            return Err(AesError::InvalidData)
+        }
+        let (nonce, encrypted) = data.split_at(NONCE_LEN);
+        Ok((GenericArray::from_slice(nonce), encrypted))
+    }
+
+    async fn create_nonce() -> Nonce {
+        let mut key = [0u8; NONCE_LEN];
         let mut rng = rng();
         rng.fill_bytes(&mut key);
         GenericArray::clone_from_slice(&key)
     }
 
-    pub async fn encrypt_data(data: &str) -> Result<Vec<u8>, AesError> {
-        dotenv().ok();
-        let key_str = env::var("AES_KEY").expect("Добавьте aes key в .env");
-        let key_array: [u8; 32] = decode(&key_str).expect("Aes key не в формате hex")
-            .as_slice().try_into().expect("Aes key не в формате Vec<u8>, неправильное кол-во символов");
-        let key = Key::<Aes256Gcm>::clone_from_slice(&key_array);
+    pub async fn encrypt_data(data: &str, config: &AesConfig) -> Result<Vec<u8>, AesError> {
+        let key = Self::load_key(config)?;
         let nonce = Self::create_nonce().await;
         let cipher = Aes256Gcm::new(&key);
 
@@ -53,21 +72,18 @@ impl Aes {
         }
     }
 
-    pub async fn decrypt_data(data: &Vec<u8>) -> Result<String, AesError> {
-        dotenv().ok();
-        let key_str = env::var("AES_KEY").expect("Добавьте aes key в .env");
-        let key_array: [u8; 32] = decode(&key_str).expect("Aes key не в формате hex")
-            .as_slice().try_into().expect("Aes key не в формате Vec<u8>, неправильное кол-во символов");
-        let key = Key::<Aes256Gcm>::clone_from_slice(&key_array);
+    pub async fn decrypt_data(data: &[u8], config: &AesConfig) -> Result<String, AesError> {
+        let key = Self::load_key(config)?;
         let cipher = Aes256Gcm::new(&key);
 
-        let nonce = GenericArray::from_slice(&data[..12]);
-        let mut vec_encrypted = Vec::new();
-        vec_encrypted.extend_from_slice(&data[12..]);
+        let (nonce, encrypted) = Self::split_nonce(data)?;
 
-        match cipher.decrypt(nonce, vec_encrypted.as_ref()) {
+        match cipher.decrypt(nonce, encrypted) {
             Ok(decrypted) => {
-                return Ok(String::from_utf8(decrypted).expect("Decrypted data не Vec<u8>"))
+                return String::from_utf8(decrypted).map_err(|e| {
+                    error!("Расшифрованные данные не UTF-8: {e}");
+                    AesError::DecryptError
+                })
             },
             Err(e) => {
                 error!("Ошибка расшифрования aes-gcm: {e}");
@@ -77,14 +93,16 @@ impl Aes {
 
     }
 
-    pub async fn encrypt_file(path: &Path) -> Result<Vec<u8>, AesError> {
-        dotenv().ok();
-        let key_hex = env::var("AES_KEY").expect("Добавьте aes key в .env");
-        let key_array: [u8; 32] = decode(key_hex).expect("Aes key не в формате hex")
-            .as_slice().try_into().expect("Aes key не в формате Vec<u8>, неправильное кол-во символов");
-        let key = Key::<Aes256Gcm>::clone_from_slice(&key_array);
+    pub async fn encrypt_file(path: &Path, config: &AesConfig) -> Result<Vec<u8>, AesError> {
+        let key = Self::load_key(config)?;
         let nonce = Self::create_nonce().await;
-        let file = fs::read(path).await.expect("file not found");
+        let file = match fs::read(path).await {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Не удалось прочитать файл {}: {e}", path.display());
+                return Err(AesError::ReadFileError)
+            }
+        };
         let cipher = Aes256Gcm::new(&key);
 
         let encrypted = match cipher.encrypt(&nonce, file.as_ref()) {
@@ -101,16 +119,12 @@ impl Aes {
         Ok(output)
     }
 
-    pub async fn decrypt_file(file: &Vec<u8>) -> Result<Vec<u8>, AesError> {
-        dotenv().ok();
-        let key_hex = env::var("AES_KEY").expect("Добавьте aes key в .env");
-        let key_array: [u8; 32] = decode(key_hex).expect("Aes key не в формате hex")
-            .as_slice().try_into().expect("Aes key не в формате Vec<u8>, неправильное кол-во символов");
-        let key = Key::<Aes256Gcm>::clone_from_slice(&key_array); 
+    pub async fn decrypt_file(file: &[u8], config: &AesConfig) -> Result<Vec<u8>, AesError> {
+        let key = Self::load_key(config)?;
         let cipher = Aes256Gcm::new(&key);
-        let nonce = GenericArray::from_slice(&file[..12]);
+        let (nonce, encrypted) = Self::split_nonce(file)?;
 
-        match cipher.decrypt(nonce, &file[12..]) {
+        match cipher.decrypt(nonce, encrypted) {
             Ok(res) => return Ok(res),
             Err(e) => {
                 error!("Ошибка расшифровки файла: {e}");
@@ -1,22 +1,49 @@
 use argon2::{
-    Algorithm, Params, Version, Argon2, PasswordHasher, PasswordVerifier, 
+    Algorithm, Version, Argon2, Params, PasswordHasher, PasswordVerifier,
     password_hash::{
         rand_core::OsRng,
         PasswordHash, SaltString
     },
 };
-use tokio::task::spawn_blocking;
 
-use crate::models::{Argon, ArgonError};
+use crate::models::{Argon, ArgonConfig, ArgonError, HashParams, HashPool};
 use log::error;
 
 
 impl Argon {
-    pub async fn verify_hash(hash: &str, value: &str) -> Result<(), ArgonError> {
+    /// Алгоритм и параметры, с которыми посчитан хеш. `None`, если строка не разбирается как argon2
+    pub fn hash_params(hash: &str) -> Option<HashParams> {
+        let parsed = PasswordHash::new(hash).ok()?;
+        let params = Params::try_from(&parsed).ok()?;
+        Some(HashParams {
+            algorithm: parsed.algorithm.as_str().to_owned(),
+            version: parsed.version?,
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+            output_len: parsed.hash?.len(),
+        })
+    }
+
+    /// Хеш посчитан не Argon2id v0x13 или с параметрами ниже `config`, его стоит пересчитать
+    pub fn needs_rehash(hash: &str, config: &ArgonConfig) -> bool {
+        match Argon::hash_params(hash) {
+            Some(params) => params.algorithm != Algorithm::Argon2id.as_str()
+                || params.version != Version::V0x13 as u32
+                || params.memory_kib < config.memory_kib
+                || params.iterations < config.iterations
+                || params.parallelism < config.parallelism
+                || params.output_len < config.output_len,
+            None => true,
+        }
+    }
+
+    /// Параметры берутся из самого хеша, поэтому `Argon2::default()` проверяет и старые хеши
+    pub async fn verify_hash(hash: &str, value: &str, hasher: &HashPool) -> Result<(), ArgonError> {
         let hash = hash.to_owned();
         let value = value.to_owned();
 
-        let process = tokio::task::spawn_blocking(move || {
+        hasher.run(move || {
             let argon2 = Argon2::default();
             let parsed_hash = match PasswordHash::new(&hash) {
                 Ok(hash) => hash,
@@ -33,54 +60,35 @@ impl Argon {
                     return Err(ArgonError::VerifyHashError);
                 }
             }
-        });
-        match process.await {
-            Ok(Ok(())) => return Ok(()),
-            Ok(Err(e)) => return Err(e),
-            Err(e) => {
-                error!("Ошибка запуска verify spawn_blocking: {e}");
-                return Err(ArgonError::TokioError)
-            }
-        }
+        }).await?
     }
 
-    pub async fn hash_str(data: &str) -> Result<String, ArgonError> {
-        let data = data.to_owned();
-
-        let hashing = spawn_blocking(move || {
-            let salt = SaltString::generate(&mut OsRng);
+    pub async fn hash_str(data: &str, config: &ArgonConfig, hasher: &HashPool) -> Result<String, ArgonError> {
+        let job = hash_job(data, config)?;
+        hasher.run(job).await?
+    }
+}
 
-            let params = match Params::new(64 * 1024, 2, 1, Some(32)) {
-                Ok(val) => val,
-                Err(e) => {
-                    error!("Ошибка создания params в argon2: {e}");
-                    return Err(ArgonError::ParamsError);
-                }
-            };
+fn hash_job(data: &str, config: &ArgonConfig) -> Result<impl FnOnce() -> Result<String, ArgonError> + Send + 'static, ArgonError> {
+    let data = data.to_owned();
+    let params = match config.params() {
+        Ok(val) => val,
+        Err(e) => {
+            error!("Ошибка создания params в argon2: {e}");
+            return Err(ArgonError::ParamsError);
+        }
+    };
 
-            let salt_string = salt.to_string();
-            let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
-            match argon2.hash_password(data.as_bytes(), &SaltString::from_b64(&salt_string).expect("Передана неверная salt строка")) {
-                Ok(hash) => return Ok(hash.to_string()),
-                Err(e) => {
-                    error!("Ошибка хеширования: {e}");
-                    return Err(ArgonError::HashError)
-                }
-            }
-        });
+    Ok(move || {
+        let salt = SaltString::generate(&mut OsRng);
 
-        match hashing.await {
-            Ok(Ok(hash)) => return Ok(hash),
-            Ok(Err(e)) => {
-                error!("Не удалось хешировать данные: {e}");
-                return Err(ArgonError::HashError)
-            },
+        let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params);
+        match argon2.hash_password(data.as_bytes(), &salt) {
+            Ok(hash) => return Ok(hash.to_string()),
             Err(e) => {
-                error!("Ошибка запуска hash spawn_blocking: {e}");
-                return Err(ArgonError::TokioError)
+                error!("Ошибка хеширования: {e}");
+                return Err(ArgonError::HashError)
             }
         }
-    }
-
-
+    })
 }
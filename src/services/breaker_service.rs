@@ -0,0 +1,123 @@
+use std::{future::Future, sync::{atomic::Ordering, Arc, Weak}, time::Duration};
+use async_trait::async_trait;
+use log::{info, warn};
+
+use crate::models::{BreakerCache, Cache, CircuitBreaker, CustomRedisError};
+
+/// Ключ, который читает фоновая проверка. Отсутствие ключа - тоже ответ
+const PROBE_KEY: &str = "rp:probe";
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32) -> Self {
+        CircuitBreaker { threshold, ..Default::default() }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    /// Ошибки соединения считаются сбоями, отсутствующий ключ и неверное значение - нет
+    fn record<T>(&self, result: &Result<T, CustomRedisError>) {
+        match result {
+            Ok(_) | Err(CustomRedisError::NoneError) | Err(CustomRedisError::ValueError(_)) => {
+                self.failures.store(0, Ordering::Relaxed);
+            },
+            Err(_) => {
+                self.failures_total.fetch_add(1, Ordering::Relaxed);
+                let failures = self.failures.fetch_add(1, Ordering::Relaxed) + 1;
+                if failures >= self.threshold && !self.open.swap(true, Ordering::Relaxed) {
+                    self.opened_total.fetch_add(1, Ordering::Relaxed);
+                    warn!("Redis не отвечает {failures} раз подряд, кеш отключён");
+                }
+            },
+        }
+    }
+
+    fn close(&self) {
+        self.failures.store(0, Ordering::Relaxed);
+        if self.open.swap(false, Ordering::Relaxed) {
+            info!("Redis снова отвечает, кеш включён");
+        }
+    }
+
+    /// Метрики в текстовом формате Prometheus
+    pub fn metrics(&self) -> String {
+        format!(
+            "# TYPE rp_cache_circuit_open gauge\n\
+            rp_cache_circuit_open {}\n\
+            # TYPE rp_cache_failures_total counter\n\
+            rp_cache_failures_total {}\n\
+            # TYPE rp_cache_bypassed_total counter\n\
+            rp_cache_bypassed_total {}\n\
+            # TYPE rp_cache_circuit_opened_total counter\n\
+            rp_cache_circuit_opened_total {}\n",
+            self.is_open() as u8,
+            self.failures_total.load(Ordering::Relaxed),
+            self.bypassed_total.load(Ordering::Relaxed),
+            self.opened_total.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl BreakerCache {
+    /// `inner` за `breaker`. Пока breaker открыт, фоновая задача раз в `probe_interval`
+    /// обращается к `inner` и закрывает breaker после первого ответа
+    pub fn wrap(inner: Arc<dyn Cache>, breaker: Arc<CircuitBreaker>, probe_interval: Duration) -> Arc<dyn Cache> {
+        tokio::spawn(probe(Arc::clone(&inner), Arc::downgrade(&breaker), probe_interval));
+        Arc::new(BreakerCache { inner, breaker })
+    }
+
+    async fn call<T>(&self, op: impl Future<Output = Result<T, CustomRedisError>>) -> Result<T, CustomRedisError> {
+        if self.breaker.is_open() {
+            self.breaker.bypassed_total.fetch_add(1, Ordering::Relaxed);
+            return Err(CustomRedisError::Unavailable)
+        }
+
+        let result = op.await;
+        self.breaker.record(&result);
+        result
+    }
+}
+
+/// Задача завершается вместе с breaker
+async fn probe(inner: Arc<dyn Cache>, breaker: Weak<CircuitBreaker>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(breaker) = breaker.upgrade() else { return };
+        if !breaker.is_open() {
+            continue
+        }
+
+        match inner.get_str(PROBE_KEY).await {
+            Ok(_) | Err(CustomRedisError::NoneError) => breaker.close(),
+            Err(e) => info!("Redis всё ещё недоступен: {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for BreakerCache {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError> {
+        self.call(self.inner.get_str(key)).await
+    }
+
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        self.call(self.inner.set_str(key, value, ttl)).await
+    }
+
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        self.call(self.inner.mget_str(keys)).await
+    }
+
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        self.call(self.inner.mset_str(items, ttl)).await
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError> {
+        self.call(self.inner.del(key)).await
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError> {
+        self.call(self.inner.incr(key)).await
+    }
+}
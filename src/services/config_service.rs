@@ -0,0 +1,211 @@
+use argon2::Params;
+use dotenv::dotenv;
+use std::{env, fmt::Display, io::ErrorKind, net::SocketAddr, str::FromStr};
+use log::info;
+
+use crate::models::{AesConfig, ArgonConfig, Config, ConfigError, DatabaseConfig, JwtConfig, RedisConfig, ServerConfig};
+
+/// Файл настроек по умолчанию, его отсутствие не ошибка
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig { bind: SocketAddr::from(([0, 0, 0, 0], 3000)), legacy_auth_routes: true }
+    }
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        RedisConfig {
+            url: "redis://127.0.0.1:6379".to_owned(),
+            user_ttl: 600,
+            page_ttl: 60,
+            negative_ttl: 30,
+            ttl_jitter: 10,
+            l1_ttl: 0,
+            l1_capacity: 10000,
+            failure_threshold: 5,
+            probe_interval: 5,
+            timeout_ms: 500,
+        }
+    }
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig { keyring: "keyring.json".to_owned(), access_ttl: 15 * 60, refresh_ttl_days: 30, refresh_grace: 10, purge_interval: 3600, refresh_token_key: String::new(), revocation_fail_open: false }
+    }
+}
+
+impl Default for ArgonConfig {
+    fn default() -> Self {
+        ArgonConfig { memory_kib: 64 * 1024, iterations: 2, parallelism: 1, output_len: 32, workers: 4, queue: 32, retry_after: 1 }
+    }
+}
+
+impl ArgonConfig {
+    pub fn params(&self) -> Result<Params, argon2::Error> {
+        Params::new(self.memory_kib, self.iterations, self.parallelism, Some(self.output_len))
+    }
+}
+
+fn env_override<T>(lookup: &impl Fn(&str) -> Option<String>, name: &'static str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = lookup(name) {
+        *target = value.trim().parse()
+            .map_err(|e: T::Err| ConfigError::Env { name, message: e.to_string() })?;
+    }
+    Ok(())
+}
+
+impl Config {
+    /// Загружает настройки один раз при старте: файл, затем .env и переменные окружения, затем проверка
+    pub fn load() -> Result<Config, ConfigError> {
+        dotenv().ok();
+        let mut config = match env::var("APP_CONFIG") {
+            Ok(path) => Config::from_file(&path)?,
+            Err(_) => match std::fs::read_to_string(DEFAULT_CONFIG_PATH) {
+                Ok(data) => Config::from_toml(DEFAULT_CONFIG_PATH, &data)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => Config::default(),
+                Err(source) => return Err(ConfigError::Read { path: DEFAULT_CONFIG_PATH.to_owned(), source }),
+            },
+        };
+
+        config.apply_overrides(|name| env::var(name).ok())?;
+        config.validate()?;
+        info!("Настройки загружены, адрес сервера: {}", config.server.bind);
+        Ok(config)
+    }
+
+    pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_owned(), source })?;
+        Config::from_toml(path, &data)
+    }
+
+    pub fn from_toml(path: &str, data: &str) -> Result<Config, ConfigError> {
+        toml::from_str(data).map_err(|source| ConfigError::Parse { path: path.to_owned(), source })
+    }
+
+    /// Переменные окружения важнее файла, `lookup` подменяется в тестах
+    pub fn apply_overrides(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        env_override(&lookup, "APP_BIND", &mut self.server.bind)?;
+        env_override(&lookup, "LEGACY_AUTH_ROUTES", &mut self.server.legacy_auth_routes)?;
+        env_override(&lookup, "DATABASE_URL", &mut self.database.url)?;
+        env_override(&lookup, "REDIS_URL", &mut self.redis.url)?;
+        env_override(&lookup, "REDIS_USER_TTL", &mut self.redis.user_ttl)?;
+        env_override(&lookup, "REDIS_PAGE_TTL", &mut self.redis.page_ttl)?;
+        env_override(&lookup, "REDIS_NEGATIVE_TTL", &mut self.redis.negative_ttl)?;
+        env_override(&lookup, "REDIS_TTL_JITTER", &mut self.redis.ttl_jitter)?;
+        env_override(&lookup, "REDIS_L1_TTL", &mut self.redis.l1_ttl)?;
+        env_override(&lookup, "REDIS_L1_CAPACITY", &mut self.redis.l1_capacity)?;
+        env_override(&lookup, "REDIS_FAILURE_THRESHOLD", &mut self.redis.failure_threshold)?;
+        env_override(&lookup, "REDIS_PROBE_INTERVAL", &mut self.redis.probe_interval)?;
+        env_override(&lookup, "REDIS_TIMEOUT_MS", &mut self.redis.timeout_ms)?;
+        env_override(&lookup, "JWT_KEYRING", &mut self.jwt.keyring)?;
+        env_override(&lookup, "ACCESS_TOKEN_TTL", &mut self.jwt.access_ttl)?;
+        env_override(&lookup, "REFRESH_TOKEN_TTL_DAYS", &mut self.jwt.refresh_ttl_days)?;
+        env_override(&lookup, "REFRESH_GRACE_SECS", &mut self.jwt.refresh_grace)?;
+        env_override(&lookup, "REFRESH_PURGE_INTERVAL", &mut self.jwt.purge_interval)?;
+        env_override(&lookup, "REFRESH_TOKEN_KEY", &mut self.jwt.refresh_token_key)?;
+        env_override(&lookup, "REVOCATION_FAIL_OPEN", &mut self.jwt.revocation_fail_open)?;
+        env_override(&lookup, "ARGON_MEMORY_KIB", &mut self.argon.memory_kib)?;
+        env_override(&lookup, "ARGON_ITERATIONS", &mut self.argon.iterations)?;
+        env_override(&lookup, "ARGON_PARALLELISM", &mut self.argon.parallelism)?;
+        env_override(&lookup, "ARGON_WORKERS", &mut self.argon.workers)?;
+        env_override(&lookup, "ARGON_QUEUE", &mut self.argon.queue)?;
+        env_override(&lookup, "ARGON_RETRY_AFTER", &mut self.argon.retry_after)?;
+        env_override(&lookup, "AES_KEY", &mut self.aes.key)?;
+        Ok(())
+    }
+
+    /// Собирает все ошибки сразу, чтобы не исправлять настройки по одной
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let mut errors = Vec::new();
+
+        if self.database.url.is_empty() {
+            errors.push("database.url (DATABASE_URL) must be set".to_owned());
+        } else if !self.database.url.starts_with("postgres://") && !self.database.url.starts_with("postgresql://") {
+            errors.push("database.url must start with postgres:// or postgresql://".to_owned());
+        }
+        if !self.redis.url.starts_with("redis://") && !self.redis.url.starts_with("rediss://") {
+            errors.push("redis.url must start with redis:// or rediss://".to_owned());
+        }
+        if self.redis.user_ttl == 0 || self.redis.page_ttl == 0 {
+            errors.push("redis.user_ttl and redis.page_ttl must be greater than 0".to_owned());
+        }
+        if self.redis.negative_ttl == 0 {
+            errors.push("redis.negative_ttl must be greater than 0".to_owned());
+        }
+        if self.redis.ttl_jitter > 100 {
+            errors.push("redis.ttl_jitter must be at most 100".to_owned());
+        }
+        if self.redis.l1_ttl > 0 && self.redis.l1_capacity == 0 {
+            errors.push("redis.l1_capacity must be greater than 0 when redis.l1_ttl is set".to_owned());
+        }
+        if self.redis.failure_threshold == 0 || self.redis.probe_interval == 0 || self.redis.timeout_ms == 0 {
+            errors.push("redis.failure_threshold, redis.probe_interval and redis.timeout_ms must be greater than 0".to_owned());
+        }
+        // access токены, которым осталось меньше 10 секунд, отклоняются при проверке
+        if self.jwt.access_ttl < 60 {
+            errors.push("jwt.access_ttl must be at least 60 seconds".to_owned());
+        }
+        if self.jwt.refresh_ttl_days < 1 {
+            errors.push("jwt.refresh_ttl_days must be at least 1".to_owned());
+        }
+        if self.jwt.refresh_grace as i64 >= self.jwt.access_ttl {
+            errors.push("jwt.refresh_grace must be less than jwt.access_ttl".to_owned());
+        }
+        if self.jwt.purge_interval == 0 {
+            errors.push("jwt.purge_interval must be greater than 0".to_owned());
+        }
+        if self.jwt.refresh_token_key.is_empty() {
+            errors.push("jwt.refresh_token_key (REFRESH_TOKEN_KEY) must be set".to_owned());
+        } else if self.jwt.refresh_token_key.len() != 64 || hex::decode(&self.jwt.refresh_token_key).is_err() {
+            errors.push("jwt.refresh_token_key must be 32 bytes in hex (64 characters)".to_owned());
+        }
+        if let Err(e) = self.argon.params() {
+            errors.push(format!("argon params are invalid: {e}"));
+        }
+        if self.argon.workers == 0 || self.argon.retry_after == 0 {
+            errors.push("argon.workers and argon.retry_after must be greater than 0".to_owned());
+        }
+        if self.aes.key.is_empty() {
+            errors.push("aes.key (AES_KEY) must be set".to_owned());
+        } else if self.aes.key.len() != 64 || hex::decode(&self.aes.key).is_err() {
+            errors.push("aes.key must be 32 bytes in hex (64 characters)".to_owned());
+        }
+
+        if errors.is_empty() { Ok(()) } else { Err(ConfigError::Invalid(errors)) }
+    }
+}
+
+/// Без значений: url базы, ключ AES и ключ refresh токенов содержат секреты
+impl std::fmt::Debug for DatabaseConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DatabaseConfig { url: *** }")
+    }
+}
+
+impl std::fmt::Debug for JwtConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("JwtConfig")
+            .field("keyring", &self.keyring)
+            .field("access_ttl", &self.access_ttl)
+            .field("refresh_ttl_days", &self.refresh_ttl_days)
+            .field("refresh_grace", &self.refresh_grace)
+            .field("purge_interval", &self.purge_interval)
+            .field("refresh_token_key", &"***")
+            .field("revocation_fail_open", &self.revocation_fail_open)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for AesConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AesConfig { key: *** }")
+    }
+}
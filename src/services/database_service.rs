@@ -1,60 +1,116 @@
-use std::{env, sync::Arc};
-use deadpool_redis::Pool;
-use sqlx::{PgPool, query, query_as, Transaction};
-use dotenv::dotenv;
-use log::error;
+use std::{future::Future, sync::{Arc, Weak}, time::Duration};
+use sqlx::PgPool;
+use log::{error, info};
 
-use crate::models::{Argon, DataBase, DataBaseError, HashExtractDb, Jwt, TimeCustom, User, Redis};
+use crate::models::{Argon, Cache, CacheNamespace, Config, DataBase, DatabaseConfig, DataBaseError, DeviceInfo, HashPool, Jwt, KeyStore, RefreshTokenKey, RefreshTokenRecord, RefreshTokenRepository, StartupError, TimeCustom, OwnProfile, PasswordHashReport, PasswordParamsCount, PublicProfile, RedisConfig, SingleFlight, User, UserListQuery, UserPage, UserPatch, UserRepository};
 
+/// Профили пользователей по id и по nickname
+pub const USERS_BY_ID: CacheNamespace = CacheNamespace::new("user", 1);
+pub const USERS_BY_NICKNAME: CacheNamespace = CacheNamespace::new("user_nick", 1);
+
+/// Страницы `/all`, в ключе ещё и номер версии из `USERS_VERSION_KEY`
+pub const USERS_PAGES: CacheNamespace = CacheNamespace::new("users:page", 1);
+
+/// Версия кеша страниц `/all`, увеличивается при любом изменении пользователей
+pub(crate) const USERS_VERSION_KEY: &str = "users:version";
+
+async fn cache_profile(cache: &dyn Cache, key: &str, user: &OwnProfile, ttl: u64) {
+    if let Err(e) = cache.set(key, user, ttl).await {
+        error!("Не удалось сохранить {key} в кеш: {e}");
+    }
+}
+
+/// Читает профиль из кеша: `Ok(None)` - ключа нет, закешированное отсутствие - `NotFound`
+async fn cached_profile(cache: &dyn Cache, key: &str) -> Result<Option<OwnProfile>, DataBaseError> {
+    match cache.get::<Option<OwnProfile>>(key).await {
+        Ok(Some(Some(user))) => Ok(Some(user)),
+        Ok(Some(None)) => Err(DataBaseError::NotFound),
+        _ => Ok(None),
+    }
+}
+
+/// Загружает профиль из хранилища и кеширует его, а ненайденного пользователя
+/// запоминает на `negative_ttl`, чтобы повторные запросы не доходили до БД
+async fn fill_profile<F>(key: String, cache: Arc<dyn Cache>, config: RedisConfig, load: F) -> Result<OwnProfile, DataBaseError>
+where
+    F: Future<Output = Result<OwnProfile, DataBaseError>>,
+{
+    match load.await {
+        Ok(user) => {
+            cache_profile(&*cache, &key, &user, config.jitter(config.user_ttl)).await;
+            Ok(user)
+        },
+        Err(DataBaseError::NotFound) => {
+            if let Err(e) = cache.set(&key, &None::<OwnProfile>, config.jitter(config.negative_ttl)).await {
+                error!("Не удалось сохранить {key} в кеш: {e}");
+            }
+            Err(DataBaseError::NotFound)
+        },
+        Err(e) => Err(e),
+    }
+}
+
+/// Фоновая очистка истёкших refresh токенов, завершается вместе с хранилищем
+async fn purge_tokens(tokens: Weak<dyn RefreshTokenRepository>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(tokens) = tokens.upgrade() else { return };
+        match tokens.purge_expired_tokens().await {
+            Ok(0) => (),
+            Ok(count) => info!("Удалено истёкших refresh токенов: {count}"),
+            Err(e) => error!("Не удалось удалить истёкшие refresh токены: {e}"),
+        }
+    }
+}
+
+/// Запросы к хранилищам через `UserRepository`/`RefreshTokenRepository`
+/// вместе с кешем профилей и хешированием паролей и refresh токенов
 impl DataBase {
-    pub async fn create_connection() -> PgPool {
-        dotenv().ok();
-        let db_url = env::var("DATABASE_URL").expect("DATABASE URL must be set");
-        let pgpool = PgPool::connect(&db_url).await.expect("Error connecting to database");
-        pgpool
-    }
-
-    pub async fn verify_ref_token(user_id: &str, token: &str, jti: &str, pool: Arc<PgPool>) -> Result<(), DataBaseError> {
-        let req = r#"SELECT token_hash FROM refresh_tokens WHERE user_id = $1 and jti = $2"#;
-        match sqlx::query_as::<_, HashExtractDb>(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(jti).fetch_one(&*Arc::clone(&pool)).await {
-            Ok(res) => match Argon::verify_hash(&res.token_hash, token).await {
-                Ok(_) => return Ok(()),
-                Err(e) => {
-                    error!("Ошибка проверки хеша токена: {e}");
-                    return  Err(DataBaseError::SomeArgonError(e));
-                }
-            } 
+    pub async fn create_connection(config: &DatabaseConfig) -> Result<PgPool, StartupError> {
+        match PgPool::connect(&config.url).await {
+            Ok(pool) => return Ok(pool),
             Err(e) => {
-                error!("Ошибка поиска токена в базе данных: {e}");
-                return Err(DataBaseError::NotFound) 
+                error!("Не удалось подключиться к БД: {e}");
+                return Err(StartupError::Database(e))
             }
         }
-        
     }
 
-    pub async fn del_ref_token(user_id: &str, jti: &str, pool: Arc<PgPool>) -> Result<(), DataBaseError> {
-        let req = r#"DELETE FROM refresh_tokens WHERE user_id = $1 and jti = $2 "#;
-        let res = query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(jti).execute(&*pool).await;
-        match res {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                error!("Не удалось удалить использованный refresh токен из БД: {e}");
-                return Err(DataBaseError::SqlxError);
+    /// Раз в `interval` удаляет истёкшие refresh токены. Задача завершается вместе с `tokens`
+    pub fn spawn_token_purge(tokens: &Arc<dyn RefreshTokenRepository>, interval: Duration) {
+        tokio::spawn(purge_tokens(Arc::downgrade(tokens), interval));
+    }
+
+    pub async fn verify_ref_token(user_id: &str, token: &str, jti: &str, tokens: Arc<dyn RefreshTokenRepository>, hasher: Arc<HashPool>, key: &RefreshTokenKey) -> Result<(), DataBaseError> {
+        match tokens.find_token_by_digest(&Jwt::token_digest(token, key)).await {
+            Ok((owner, token_jti)) if owner.to_string() == user_id && token_jti == jti => return Ok(()),
+            Ok(_) => {
+                error!("Digest refresh токена {jti} принадлежит другому токену");
+                return Err(DataBaseError::NotFound)
             },
+            Err(DataBaseError::NotFound) => (),
+            Err(e) => return Err(e),
+        }
+
+        // токены, сохранённые до перехода на HMAC, проверяются по Argon2 хешу, пока не истекут
+        let token_hash = tokens.get_token_hash(user_id, jti).await?;
+        if !token_hash.starts_with("$argon2") {
+            return Err(DataBaseError::NotFound)
+        }
+        match Argon::verify_hash(&token_hash, token, &hasher).await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                error!("Ошибка проверки хеша токена: {e}");
+                return  Err(DataBaseError::SomeArgonError(e));
+            }
         }
     }
 
-    pub async fn save_ref_token(token: &str, pool: Arc<PgPool>) -> Result<(), DataBaseError> {
-        if let Ok(claims) = Jwt::verify_ref_token(token, Arc::clone(&pool), false).await {
+    pub async fn save_ref_token(token: &str, family_id: &str, device: &DeviceInfo, keys: Arc<KeyStore>, tokens: Arc<dyn RefreshTokenRepository>, hasher: Arc<HashPool>) -> Result<(), DataBaseError> {
+        if let Ok(claims) = Jwt::verify_ref_token(token, Arc::clone(&keys), Arc::clone(&tokens), hasher, false).await {
+
+            let token_hash = Jwt::token_digest(token, &keys.refresh_key);
 
-            let token_hash = match Argon::hash_str(token).await {
-                Ok(hash) => hash,
-                Err(e) => {
-                    error!("Не удалось хешировать refresh токен: {e}");
-                    return Err(DataBaseError::SomeArgonError(e));
-                }
-            };
-            
             let expires = match TimeCustom::from_usize_to_timestampz(claims.exp).await {
                 Ok(time) => time,
                 Err(e) => {
@@ -71,176 +127,169 @@ impl DataBase {
                 }
             };
 
-            let sub: i64 = claims.sub.parse().expect("Не удалось запарсить claims.sub в i64 из usize");
-
-            let req = r#"INSERT INTO refresh_tokens (jti, user_id, token_hash, expires_at, created_at) VALUES ($1, $2, $3, $4, $5)"#;
-            match query(req).bind(claims.jti).bind(sub).bind(token_hash).bind(expires).bind(created).execute(&*Arc::clone(&pool)).await {
-                Ok(_) => return Ok(()),
+            let sub: i64 = match claims.sub.parse() {
+                Ok(sub) => sub,
                 Err(e) => {
-                    error!("Не удалось сохранить refresh токен в БД: {e}");
-                    return Err(DataBaseError::SaveError)
+                    error!("Не удалось запарсить claims.sub в i64: {e}");
+                    return Err(DataBaseError::NonValidToken)
                 }
-            }
+            };
+
+            let record = RefreshTokenRecord {
+                jti: claims.jti,
+                user_id: sub,
+                token_hash,
+                family_id: family_id.to_owned(),
+                created_at: created,
+                expires_at: expires,
+                last_used_at: created,
+                rotated_at: None,
+                device: device.clone(),
+            };
+            tokens.save_ref_token(&record).await
         } else {
             Err(DataBaseError::NonValidToken)
         }
     }
 
-    pub async fn save_user(nickname: &str, name: &str, password: &str, pool: &mut sqlx::Transaction<'static, sqlx::Postgres>, redis_pool: Arc<Pool>) -> Result<User, DataBaseError> {
-        let password = match Argon::hash_str(password).await {
+    pub async fn save_user(nickname: &str, name: &str, password: &str, users: Arc<dyn UserRepository>, cache: Arc<dyn Cache>, hasher: Arc<HashPool>, config: &Config) -> Result<User, DataBaseError> {
+        let password = match Argon::hash_str(password, &config.argon, &hasher).await {
             Ok(hash) => hash,
             Err(e) => {
                 error!("Не удалось хешировать пароль: {e}");
                 return Err(DataBaseError::SomeArgonError(e));
             }
         };
-        
-        let req = r#"INSERT INTO users (nickname, name, password) VALUES ($1, $2, $3) RETURNING id, nickname, name, password"#;
-        match query_as::<_, User>(req).bind(nickname).bind(name).bind(password).fetch_one(&mut **pool).await {
-            Ok(val) => {
-                match Redis::redis_set(Arc::clone(&redis_pool), &format!("user:{}", val.id), vec![val.clone()]).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Ошибка вызова redis_set: {e}")
-                    }
-                }
 
-                match Redis::redis_set(Arc::clone(&redis_pool), &format!("user_nick:{}", val.nickname), vec![val.clone()]).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Ошибка вызова redis_set: {e}")
-                    }
-                }
-                return Ok(val)
-            },
-            Err(e) => {
-                error!("Ошибка добавления нового пользователя в БД: {e}");
-                return Err(DataBaseError::SaveError);
-            }
+        let user = users.create_user(nickname, name, &password).await?;
+        let profile = OwnProfile::from(&user);
+        let items = [(USERS_BY_ID.key(user.id), profile.clone()), (USERS_BY_NICKNAME.key(&user.nickname), profile)];
+        if let Err(e) = cache.mset(&items, config.redis.jitter(config.redis.user_ttl)).await {
+            error!("Не удалось сохранить пользователя {} в кеш: {e}", user.id);
         }
+        Ok(user)
     }
 
-    pub async fn get_user(nickname: &str, pool: Arc<PgPool>, redis_pool: Arc<Pool>) -> Result<User, DataBaseError> {
-        match Redis::redis_get(Arc::clone(&redis_pool), &format!("user_nick:{}", nickname)).await {
-            Ok(vec) => {
-                return Ok(vec.first().unwrap().to_owned())
-            },
-            Err(e) => {
-                error!("Ошибка поиска user в redis: {e}");
+    /// Откатывает `save_user`, если регистрация не завершилась: удаляет пользователя и его кеш
+    pub async fn discard_user(user: &User, users: Arc<dyn UserRepository>, cache: Arc<dyn Cache>) {
+        if let Err(e) = users.delete_user(user.id).await {
+            error!("Не удалось удалить пользователя {} после неудачной регистрации: {e}", user.id);
+        }
+        for key in [USERS_BY_ID.key(user.id), USERS_BY_NICKNAME.key(&user.nickname)] {
+            if let Err(e) = cache.del(&key).await {
+                error!("Не удалось удалить {key} из Redis: {e}");
             }
-        };
+        }
+    }
 
-        let req = r#"SELECT * FROM users WHERE nickname = $1"#;
+    /// Одновременные промахи по одному nickname идут в хранилище одним запросом
+    pub async fn get_user(nickname: &str, users: Arc<dyn UserRepository>, cache: Arc<dyn Cache>, flights: Arc<SingleFlight>, config: &Config) -> Result<OwnProfile, DataBaseError> {
+        let key = USERS_BY_NICKNAME.key(nickname);
+        if let Some(user) = cached_profile(&*cache, &key).await? {
+            return Ok(user)
+        }
 
-        match query_as::<_, User>(req).bind(nickname).fetch_one(&*Arc::clone(&pool)).await {
-            Ok(user) => {
-                match Redis::redis_set(Arc::clone(&redis_pool), &format!("user_nick:{}", &user.nickname), vec![user.clone()]).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Ошибка вызова redis_set: {e}")
-                    }
-                }
+        let nickname = nickname.to_owned();
+        let fill = fill_profile(key.clone(), cache, config.redis.clone(), async move { users.get_user(&nickname).await });
+        flights.run(&key, fill).await
+    }
 
-                return Ok(user)
-            },
-            Err(e) => {
-                error!("Не удалось найти пользователя в БД: {e}");
-                return  Err(DataBaseError::NotFound);
-            }
+    /// Сбрасывает все закешированные страницы `/all`: ключи страниц содержат версию
+    pub async fn invalidate_users_pages(cache: Arc<dyn Cache>) {
+        if let Err(e) = cache.incr(USERS_VERSION_KEY).await {
+            error!("Не удалось сбросить кеш страниц пользователей: {e}");
         }
     }
 
-    pub async fn get_all_users(pool: Arc<PgPool>, redis_pool: Arc<Pool>) -> Result<Vec<User>, DataBaseError> {
-        match Redis::redis_get(Arc::clone(&redis_pool), "user:all").await {
-            Ok(vec) => {
-                return Ok(vec)
-            },
-            Err(e) => {
-                error!("Ошибка поиска all user в redis: {e}");
-            }
-        };
+    pub async fn get_users_page(list: &UserListQuery, users: Arc<dyn UserRepository>, cache: Arc<dyn Cache>, flights: Arc<SingleFlight>, config: &Config) -> Result<UserPage, DataBaseError> {
+        let limit = list.page_limit();
+        let prefix = list.q.as_deref().map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
 
-        let req = r#"SELECT * FROM users"#;
-        let res = query_as::<_, User>(req).fetch_all(&*pool).await;
-        match res {
-            Ok(users) => {
-                match Redis::redis_set(Arc::clone(&redis_pool), "user:all", users.clone()).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Ошибка вызова redis_set: {e}")
-                    }
-                }
+        let version = cache.get_str(USERS_VERSION_KEY).await.unwrap_or("0".to_owned());
+        let key = USERS_PAGES.key(format!("{version}:{:?}:{:?}:{}:{limit}:{}",
+            list.sort, list.order, list.after.map(|id| id.to_string()).unwrap_or_default(), prefix.as_deref().unwrap_or("")));
+        if let Ok(Some(page)) = cache.get::<UserPage>(&key).await {
+            return Ok(page)
+        }
 
-                return Ok(users)
-            },
-            Err(e) => {
-                error!("Ошибка получения всех пользователей из ДБ: {e}");
-                return Err(DataBaseError::SqlxError)
-            }
+        let list = list.clone();
+        let page_key = key.clone();
+        let ttl = config.redis.jitter(config.redis.page_ttl);
+        flights.run(&key, async move {
+            // лишняя строка показывает, что есть следующая страница
+            let mut found = users.find_users(&list, limit + 1).await?;
+            let next_after = if found.len() as i64 > limit {
+                found.truncate(limit as usize);
+                found.last().map(|user| user.id)
+            } else {
+                None
+            };
+            let page = UserPage { items: found.into_iter().map(PublicProfile::from).collect(), next_after };
+
+            cache.set(&page_key, &page, ttl).await.unwrap_or(());
+            Ok(page)
+        }).await
+    }
+
+    pub async fn get_user_by_id(id: &str, users: Arc<dyn UserRepository>, cache: Arc<dyn Cache>, flights: Arc<SingleFlight>, config: &Config) -> Result<OwnProfile, DataBaseError> {
+        let key = USERS_BY_ID.key(id);
+        if let Some(user) = cached_profile(&*cache, &key).await? {
+            return Ok(user)
         }
+
+        let id = id.to_owned();
+        let fill = fill_profile(key.clone(), cache, config.redis.clone(), async move { users.get_user_by_id(&id).await });
+        flights.run(&key, fill).await
     }
 
-    pub async fn get_user_by_id(id: &str, pool: Arc<PgPool>, redis_pool: Arc<Pool>) -> Result<User, DataBaseError> {
-        match Redis::redis_get(Arc::clone(&redis_pool), &format!("user:{}", id)).await {
-            Ok(vec) => {
-                return Ok(vec.first().unwrap().to_owned())
-            },
-            Err(e) => {
-                error!("Ошибка поиска user в redis: {e}");
-            }
+    /// Применяет `UserPatch`, занятый nickname возвращается как `Conflict("nickname")`
+    pub async fn update_user(id: &str, patch: &UserPatch, users: Arc<dyn UserRepository>, hasher: Arc<HashPool>, config: &Config) -> Result<User, DataBaseError> {
+        let password = match &patch.password {
+            Some(password) => Some(Argon::hash_str(password, &config.argon, &hasher).await?),
+            None => None,
         };
 
-        let req = r#"SELECT * FROM users WHERE id = $1"#;
-        let res = query_as::<_, User>(req).bind(id.parse::<i64>().map_err(|_| DataBaseError::SomeError).unwrap()).fetch_one(&*pool).await;
-        match res {
-            Ok(user) => {
-                match Redis::redis_set(Arc::clone(&redis_pool), &format!("user:{}", id), vec![user.clone()]).await {
-                    Ok(()) => (),
-                    Err(e) => {
-                        error!("Ошибка вызова redis_set: {e}")
-                    }
-                }
-                return Ok(user)
-            },
-            Err(e) => {
-                error!("Ошибка поиска user по id: {e}");
-                return Err(DataBaseError::NotFound)
-            }
-        }
+        users.update_user(id, patch, password.as_deref()).await
     }
 
-    pub async fn update_user(id: &str, data: &str, field: &str, pool: &mut Transaction<'static, sqlx::Postgres>) -> Result<(), DataBaseError> {
-        let id = id.trim().parse::<i64>().expect("id должен быть &str");
-        let field = match field {
-            "nickname" => "nickname",
-            "name" => "name",
-            "password" => "password",
-            e@ _ => {
-                error!(r#"Поле "{e}" не существует в TABLE users"#);
-                return Err(DataBaseError::SomeError)
-            },
+    /// Пересчитывает хеш пароля по текущим `argon` настройкам после успешного входа.
+    /// Ошибки только пишутся в лог: вход от них не зависит
+    pub async fn rehash_password(user: &User, password: &str, users: Arc<dyn UserRepository>, hasher: Arc<HashPool>, config: &Config) {
+        let hash = match Argon::hash_str(password, &config.argon, &hasher).await {
+            Ok(hash) => hash,
+            Err(e) => {
+                error!("Не удалось пересчитать хеш пароля пользователя {}: {e}", user.id);
+                return
+            }
         };
 
-        let mut data = data.to_owned();
-        if field == "password" {
-            data = match Argon::hash_str(&data).await {
-                Ok(password) => password,
-                Err(e) => return Err(DataBaseError::SomeArgonError(e)) 
-            };
+        match users.replace_password_hash(user.id, &user.password, &hash).await {
+            Ok(true) => info!("Хеш пароля пользователя {} пересчитан с новыми параметрами", user.id),
+            Ok(false) => info!("Пароль пользователя {} изменился, хеш не пересчитан", user.id),
+            Err(e) => error!("Не удалось сохранить новый хеш пароля пользователя {}: {e}", user.id),
         }
+    }
 
-        let req = format!("UPDATE users SET {} = '{}' WHERE id = $1", field, data);
-        match query(&req).bind(id).execute(&mut **pool).await {
-            Ok(_) => {
-                return Ok(())
-            },
-            Err(e) => {
-                error!("Ошибка обновления пользователя: {e}");
-                return Err(DataBaseError::SqlxError)
+    /// Сколько пользователей ещё не вошли с момента изменения `argon` настроек
+    pub async fn password_hash_report(users: Arc<dyn UserRepository>, config: &Config) -> Result<PasswordHashReport, DataBaseError> {
+        let mut groups: Vec<PasswordParamsCount> = Vec::new();
+        for group in users.password_hash_groups().await? {
+            let params = Argon::hash_params(group.sample.as_str());
+            match groups.iter_mut().find(|counted| counted.params == params) {
+                Some(counted) => counted.users += group.users,
+                None => groups.push(PasswordParamsCount {
+                    legacy: Argon::needs_rehash(group.sample.as_str(), &config.argon),
+                    params,
+                    users: group.users,
+                }),
             }
         }
+        groups.sort_by_key(|group| std::cmp::Reverse(group.users));
 
+        Ok(PasswordHashReport {
+            total: groups.iter().map(|group| group.users).sum(),
+            legacy: groups.iter().filter(|group| group.legacy).map(|group| group.users).sum(),
+            groups,
+        })
     }
-        
-
 }
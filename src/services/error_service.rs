@@ -0,0 +1,120 @@
+use axum::{http::{header, StatusCode}, response::{IntoResponse, Response}, Json};
+use log::error;
+use std::any::Any;
+
+use crate::models::{ApiError, AppError, ArgonError, DataBaseError, FieldError, JwtError};
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        if self.retry_after().is_some() {
+            return StatusCode::SERVICE_UNAVAILABLE
+        }
+        match self {
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::InvalidBody { status, .. } => *status,
+            AppError::Unauthorized | AppError::InvalidCredentials | AppError::InvalidRefreshToken(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden => StatusCode::FORBIDDEN,
+            AppError::SessionNotFound => StatusCode::NOT_FOUND,
+            AppError::DataBase(DataBaseError::NotFound | DataBaseError::UnknownRole(_)) => StatusCode::NOT_FOUND,
+            AppError::DataBase(DataBaseError::Conflict(_)) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Стабильный код ошибки для клиентов, не меняется вместе с текстом
+    pub fn code(&self) -> &'static str {
+        if self.retry_after().is_some() {
+            return "overloaded"
+        }
+        match self {
+            AppError::Validation(_) => "validation_error",
+            AppError::InvalidBody { .. } => "invalid_body",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::InvalidCredentials => "invalid_credentials",
+            AppError::InvalidRefreshToken(_) => "invalid_refresh_token",
+            AppError::SessionNotFound => "session_not_found",
+            AppError::DataBase(DataBaseError::NotFound) => "user_not_found",
+            AppError::DataBase(DataBaseError::UnknownRole(_)) => "role_not_found",
+            AppError::DataBase(DataBaseError::Conflict("nickname")) => "nickname_taken",
+            AppError::DataBase(DataBaseError::Conflict(_)) => "conflict",
+            _ => "internal_error",
+        }
+    }
+
+    fn message(&self) -> String {
+        if self.retry_after().is_some() {
+            return "Server is busy, try again later".to_owned()
+        }
+        match self {
+            AppError::InvalidBody { message, .. } => message.clone(),
+            AppError::Unauthorized => "You're not authorized".to_owned(),
+            AppError::Forbidden => "You don't have access to this resource".to_owned(),
+            AppError::InvalidRefreshToken(_) => "Refresh token is invalid, expired or revoked".to_owned(),
+            AppError::DataBase(DataBaseError::NotFound) => "User not found".to_owned(),
+            AppError::DataBase(DataBaseError::UnknownRole(role)) => format!("Role \"{role}\" not found"),
+            AppError::DataBase(DataBaseError::Conflict(field)) => format!("This {field} is already taken"),
+            // подробности внутренних ошибок остаются в логе
+            _ if self.status().is_server_error() => "Error, try again later".to_owned(),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Через сколько секунд повторить запрос, если пул хеширования переполнен
+    pub fn retry_after(&self) -> Option<u64> {
+        match self {
+            AppError::Argon(ArgonError::Busy { retry_after })
+            | AppError::DataBase(DataBaseError::SomeArgonError(ArgonError::Busy { retry_after }))
+            | AppError::Jwt(JwtError::Overloaded(retry_after))
+            | AppError::InvalidRefreshToken(JwtError::Overloaded(retry_after)) => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// Ответ для `CatchPanicLayer`: паника в handler'е становится 500, а не обрывом соединения
+    pub fn from_panic(err: Box<dyn Any + Send + 'static>) -> Response {
+        let details = if let Some(text) = err.downcast_ref::<&str>() {
+            text.to_string()
+        } else if let Some(text) = err.downcast_ref::<String>() {
+            text.clone()
+        } else {
+            "unknown panic".to_owned()
+        };
+        AppError::Panic(details).into_response()
+    }
+
+    fn fields(self) -> Vec<FieldError> {
+        match self {
+            AppError::Validation(fields) => fields,
+            AppError::DataBase(DataBaseError::Conflict(field)) => vec![FieldError { field, message: "is already taken".to_owned() }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let retry_after = self.retry_after();
+        if status.is_server_error() && retry_after.is_none() {
+            error!("Ошибка обработки запроса: {self}");
+        }
+
+        let body = ApiError {
+            error: self.code(),
+            message: self.message(),
+            fields: self.fields(),
+            request_id: None,
+        };
+        let mut res = (status, Json(body.clone())).into_response();
+        if status == StatusCode::UNAUTHORIZED {
+            res.headers_mut().insert(header::WWW_AUTHENTICATE, header::HeaderValue::from_static("Bearer"));
+        }
+        if let Some(retry_after) = retry_after {
+            res.headers_mut().insert(header::RETRY_AFTER, header::HeaderValue::from(retry_after));
+        }
+        // ErrorLayer перерисует ответ по Accept и добавит request id
+        res.extensions_mut().insert(body);
+        res
+    }
+}
@@ -0,0 +1,228 @@
+use std::sync::{Arc, Weak};
+use async_trait::async_trait;
+use chrono::Duration;
+use deadpool_redis::{Pool, redis::{Client, RedisError}};
+use futures::StreamExt;
+use log::{error, info, warn};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::models::{Cache, CacheEvent, CircuitBreaker, Clock, CustomRedisError, EventBus, LayeredCache, MemoryCache, MemoryEventBus, Redis, RedisConfig, RedisEventBus};
+use crate::services::database_service::{USERS_BY_ID, USERS_BY_NICKNAME, USERS_VERSION_KEY};
+use crate::services::jwt_service::TOKENS_VALID_AFTER;
+
+/// Канал Redis pub/sub для `CacheEvent` в JSON
+pub const CACHE_EVENTS_CHANNEL: &str = "rp:cache_events";
+
+/// Сколько событий подписчик может не забрать, прежде чем пропустит их
+const EVENTS_CAPACITY: usize = 1024;
+
+impl CacheEvent {
+    /// Ключи, копии которых в L1 кеше устарели после события
+    pub fn keys(&self) -> Vec<String> {
+        match self {
+            CacheEvent::UserChanged { id, old_nickname, new_nickname } => {
+                let mut keys = vec![USERS_BY_ID.key(id), USERS_BY_NICKNAME.key(new_nickname), USERS_VERSION_KEY.to_owned()];
+                if let Some(old_nickname) = old_nickname.as_ref().filter(|old| *old != new_nickname) {
+                    keys.push(USERS_BY_NICKNAME.key(old_nickname));
+                }
+                keys
+            },
+            CacheEvent::TokensRevoked { user_id } => vec![TOKENS_VALID_AFTER.key(user_id)],
+        }
+    }
+}
+
+impl RedisEventBus {
+    /// Запускает фоновую подписку на `CACHE_EVENTS_CHANNEL`. Pub/sub занимает соединение целиком,
+    /// поэтому подписка открывает своё соединение по `config.url` и переподключается при обрыве
+    pub fn new(pool: Arc<Pool>, breaker: Arc<CircuitBreaker>, config: &RedisConfig) -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        tokio::spawn(listen(config.url.clone(), events.clone()));
+        RedisEventBus { pool, breaker, events }
+    }
+}
+
+async fn listen(url: String, events: broadcast::Sender<CacheEvent>) {
+    loop {
+        if let Err(e) = forward_messages(&url, &events).await {
+            error!("Подписка на {CACHE_EVENTS_CHANNEL} прервана: {e}");
+        }
+        // события, опубликованные без подписки, потеряны: копии в L1 истекут через l1_ttl
+        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    }
+}
+
+async fn forward_messages(url: &str, events: &broadcast::Sender<CacheEvent>) -> Result<(), RedisError> {
+    let mut pubsub = Client::open(url)?.get_async_pubsub().await?;
+    pubsub.subscribe(CACHE_EVENTS_CHANNEL).await?;
+    info!("Подписка на {CACHE_EVENTS_CHANNEL} установлена");
+
+    let mut messages = pubsub.into_on_message();
+    while let Some(message) = messages.next().await {
+        let payload: String = message.get_payload()?;
+        match serde_json::from_str::<CacheEvent>(&payload) {
+            // без подписчиков send возвращает ошибку, это не страшно
+            Ok(event) => { events.send(event).ok(); },
+            Err(e) => error!("Неизвестное событие в {CACHE_EVENTS_CHANNEL}: {e}"),
+        }
+    }
+    warn!("Соединение подписки на {CACHE_EVENTS_CHANNEL} закрыто");
+    Ok(())
+}
+
+#[async_trait]
+impl EventBus for RedisEventBus {
+    async fn publish(&self, event: &CacheEvent) -> Result<(), CustomRedisError> {
+        if self.breaker.is_open() {
+            return Err(CustomRedisError::Unavailable)
+        }
+        let message = serde_json::to_string(event).map_err(|e| CustomRedisError::ValueError(e.to_string()))?;
+        Redis::redis_publish(Arc::clone(&self.pool), CACHE_EVENTS_CHANNEL, &message).await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<CacheEvent> {
+        self.events.subscribe()
+    }
+}
+
+impl MemoryEventBus {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
+        MemoryEventBus { events }
+    }
+}
+
+impl Default for MemoryEventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl EventBus for MemoryEventBus {
+    async fn publish(&self, event: &CacheEvent) -> Result<(), CustomRedisError> {
+        self.events.send(event.clone()).ok();
+        Ok(())
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<CacheEvent> {
+        self.events.subscribe()
+    }
+}
+
+impl dyn EventBus + '_ {
+    /// Публикует событие. Ошибка только пишется в лог: общий кеш к этому моменту уже обновлён,
+    /// а копии в L1 других экземпляров истекут через `l1_ttl`
+    pub async fn notify(&self, event: CacheEvent) {
+        if let Err(e) = self.publish(&event).await {
+            error!("Не удалось опубликовать {event:?}: {e}");
+        }
+    }
+}
+
+impl LayeredCache {
+    /// `remote` с L1 кешем перед ним, если `config.l1_ttl` больше 0.
+    /// Устаревшие копии удаляются фоновой задачей по событиям из `bus`
+    pub fn wrap(remote: Arc<dyn Cache>, bus: &dyn EventBus, config: &RedisConfig, clock: Arc<dyn Clock>) -> Arc<dyn Cache> {
+        if config.l1_ttl == 0 {
+            return remote
+        }
+
+        let cache = Arc::new(LayeredCache { local: MemoryCache::new(clock), remote, ttl: config.l1_ttl, capacity: config.l1_capacity });
+        tokio::spawn(invalidate(bus.subscribe(), Arc::downgrade(&cache)));
+        cache
+    }
+
+    fn evict_local(&self, keys: &[String]) {
+        let mut entries = self.local.entries.lock().unwrap();
+        for key in keys {
+            entries.remove(key);
+        }
+    }
+
+    /// Копия в L1 живёт не дольше `self.ttl`. При переполнении сначала выбрасываются истёкшие
+    /// копии, а если их нет - весь L1
+    fn keep_local(&self, key: &str, value: &str, ttl: u64) {
+        let now = self.local.clock.now();
+        let mut entries = self.local.entries.lock().unwrap();
+        if entries.len() >= self.capacity && !entries.contains_key(key) {
+            entries.retain(|_, (_, expires_at)| *expires_at > now);
+            if entries.len() >= self.capacity {
+                entries.clear();
+            }
+        }
+        entries.insert(key.to_owned(), (value.to_owned(), now + Duration::seconds(ttl.min(self.ttl) as i64)));
+    }
+}
+
+/// Задача завершается вместе с кешем
+async fn invalidate(mut events: broadcast::Receiver<CacheEvent>, cache: Weak<LayeredCache>) {
+    loop {
+        let event = events.recv().await;
+        let Some(cache) = cache.upgrade() else { return };
+        match event {
+            Ok(event) => cache.evict_local(&event.keys()),
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Пропущено {skipped} событий кеша, L1 очищен");
+                cache.local.entries.lock().unwrap().clear();
+            },
+            Err(RecvError::Closed) => return,
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for LayeredCache {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError> {
+        if let Some(value) = self.local.live_value(key) {
+            return Ok(value)
+        }
+
+        let value = self.remote.get_str(key).await?;
+        self.keep_local(key, &value, self.ttl);
+        Ok(value)
+    }
+
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        self.remote.set_str(key, value, ttl).await?;
+        self.keep_local(key, value, ttl);
+        Ok(())
+    }
+
+    /// Отсутствующие в L1 ключи запрашиваются у общего кеша одним `mget_str`
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        let mut values: Vec<Option<String>> = keys.iter().map(|key| self.local.live_value(key)).collect();
+        let missing: Vec<String> = keys.iter().zip(&values).filter(|(_, value)| value.is_none()).map(|(key, _)| key.clone()).collect();
+        if missing.is_empty() {
+            return Ok(values)
+        }
+
+        let mut found = self.remote.mget_str(&missing).await?.into_iter();
+        for (key, value) in keys.iter().zip(values.iter_mut()).filter(|(_, value)| value.is_none()) {
+            *value = found.next().flatten();
+            if let Some(value) = value {
+                self.keep_local(key, value, self.ttl);
+            }
+        }
+        Ok(values)
+    }
+
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        self.remote.mset_str(items, ttl).await?;
+        for (key, value) in items {
+            self.keep_local(key, value, ttl);
+        }
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError> {
+        self.evict_local(&[key.to_owned()]);
+        self.remote.del(key).await
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError> {
+        let value = self.remote.incr(key).await?;
+        self.evict_local(&[key.to_owned()]);
+        Ok(value)
+    }
+}
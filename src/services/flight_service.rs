@@ -0,0 +1,49 @@
+use std::{any::Any, future::Future, sync::Arc};
+use futures::FutureExt;
+use log::error;
+
+use crate::models::{DataBaseError, Flight, SingleFlight};
+
+impl SingleFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Выполняет `fill`, если по `key` ещё никто не заполняет кеш, иначе ждёт уже запущенное
+    /// заполнение и возвращает его результат. Ключ освобождается сразу после завершения
+    pub async fn run<T, F>(&self, key: &str, fill: F) -> Result<T, DataBaseError>
+    where
+        T: Clone + Send + Sync + 'static,
+        F: Future<Output = Result<T, DataBaseError>> + Send + 'static,
+    {
+        let flight = {
+            let mut calls = self.calls.lock().unwrap();
+            match calls.get(key) {
+                Some(flight) => flight.clone(),
+                None => {
+                    let flight: Flight = async move {
+                        fill.await.map(|value| Arc::new(value) as Arc<dyn Any + Send + Sync>)
+                    }.boxed().shared();
+                    calls.insert(key.to_owned(), flight.clone());
+                    flight
+                }
+            }
+        };
+
+        let result = flight.clone().await;
+        {
+            let mut calls = self.calls.lock().unwrap();
+            if calls.get(key).is_some_and(|current| current.ptr_eq(&flight)) {
+                calls.remove(key);
+            }
+        }
+
+        match result?.downcast::<T>() {
+            Ok(value) => Ok(T::clone(&value)),
+            Err(_) => {
+                error!("Заполнение {key} вернуло значение другого типа");
+                Err(DataBaseError::SomeError)
+            }
+        }
+    }
+}
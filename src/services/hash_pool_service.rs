@@ -0,0 +1,118 @@
+use std::{fmt::Write, sync::{atomic::{AtomicUsize, Ordering}, Arc}, time::Instant};
+use tokio::{sync::{OwnedSemaphorePermit, Semaphore}, task::spawn_blocking};
+use log::{error, warn};
+
+use crate::models::{ArgonConfig, ArgonError, HashPool, HASH_WAIT_BUCKETS};
+
+/// Место в очереди освобождается и когда запрос дождался worker'а, и когда клиент ушёл раньше
+struct QueueSlot<'a>(&'a AtomicUsize);
+
+impl Drop for QueueSlot<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl HashPool {
+    pub fn new(config: &ArgonConfig) -> Self {
+        HashPool {
+            workers: Arc::new(Semaphore::new(config.workers)),
+            queue: config.queue,
+            retry_after: config.retry_after,
+            waiting: AtomicUsize::new(0),
+            rejected_total: Default::default(),
+            wait_buckets: Default::default(),
+            wait_count: Default::default(),
+            wait_micros: Default::default(),
+        }
+    }
+
+    /// Выполняет `job` в blocking потоке, когда освободится worker. Если очередь уже полна,
+    /// сразу возвращает `Busy`
+    pub async fn run<T, F>(&self, job: F) -> Result<T, ArgonError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        // пока в очереди кто-то ждёт, новые запросы встают за ним, а не занимают освободившийся worker
+        let free = match self.waiting.load(Ordering::SeqCst) {
+            0 => Arc::clone(&self.workers).try_acquire_owned().ok(),
+            _ => None,
+        };
+        let permit = match free {
+            Some(permit) => {
+                self.record_wait(0.0);
+                permit
+            },
+            None => {
+                if self.waiting.fetch_add(1, Ordering::SeqCst) >= self.queue {
+                    self.waiting.fetch_sub(1, Ordering::SeqCst);
+                    self.rejected_total.fetch_add(1, Ordering::Relaxed);
+                    warn!("Очередь хеширования заполнена, запрос отклонён");
+                    return Err(ArgonError::Busy { retry_after: self.retry_after })
+                }
+                let _slot = QueueSlot(&self.waiting);
+                self.acquire().await?
+            },
+        };
+        self.spawn(permit, job).await
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, ArgonError> {
+        let started = Instant::now();
+        let permit = Arc::clone(&self.workers).acquire_owned().await.map_err(|e| {
+            error!("Пул хеширования закрыт: {e}");
+            ArgonError::TokioError
+        })?;
+        self.record_wait(started.elapsed().as_secs_f64());
+        Ok(permit)
+    }
+
+    /// Permit переходит в blocking поток и освобождается только после `job`: если клиент ушёл
+    /// и запрос отменён, хеш всё равно досчитывается и занимает worker до конца
+    async fn spawn<T, F>(&self, permit: OwnedSemaphorePermit, job: F) -> Result<T, ArgonError>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> T + Send + 'static,
+    {
+        spawn_blocking(move || {
+            let result = job();
+            drop(permit);
+            result
+        }).await.map_err(|e| {
+            error!("Ошибка запуска spawn_blocking: {e}");
+            ArgonError::TokioError
+        })
+    }
+
+    fn record_wait(&self, secs: f64) {
+        for (bucket, bound) in self.wait_buckets.iter().zip(HASH_WAIT_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.wait_count.fetch_add(1, Ordering::Relaxed);
+        self.wait_micros.fetch_add((secs * 1_000_000.0) as u64, Ordering::Relaxed);
+    }
+
+    /// Метрики в текстовом формате Prometheus
+    pub fn metrics(&self) -> String {
+        let count = self.wait_count.load(Ordering::Relaxed);
+        let mut out = String::from("# TYPE rp_hash_queue_wait_seconds histogram\n");
+        for (bucket, bound) in self.wait_buckets.iter().zip(HASH_WAIT_BUCKETS) {
+            writeln!(out, "rp_hash_queue_wait_seconds_bucket{{le=\"{bound}\"}} {}", bucket.load(Ordering::Relaxed)).unwrap_or(());
+        }
+        writeln!(out, "rp_hash_queue_wait_seconds_bucket{{le=\"+Inf\"}} {count}").unwrap_or(());
+        writeln!(out, "rp_hash_queue_wait_seconds_sum {}", self.wait_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0).unwrap_or(());
+        writeln!(out, "rp_hash_queue_wait_seconds_count {count}").unwrap_or(());
+        write!(out,
+            "# TYPE rp_hash_queued gauge\n\
+            rp_hash_queued {}\n\
+            # TYPE rp_hash_rejected_total counter\n\
+            rp_hash_rejected_total {}\n",
+            self.waiting.load(Ordering::SeqCst),
+            self.rejected_total.load(Ordering::Relaxed),
+        ).unwrap_or(());
+        out
+    }
+}
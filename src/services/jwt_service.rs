@@ -1,50 +1,106 @@
+use axum::http::{header, HeaderMap};
 use axum_extra::extract::CookieJar;
-use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
-use chrono::{Duration, Utc};
+use jsonwebtoken::{decode, encode, Algorithm, Header, Validation};
+use chrono::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
 use uuid::Uuid;
-use tokio::fs;
 use std::{result::Result, sync::Arc};
 use log::{error, info};
-use sqlx::PgPool;
 
-use crate::models::{Claims, DataBase, Jwt, JwtError};
+use crate::models::{AppState, ArgonError, Cache, CacheNamespace, Claims, DataBase, DataBaseError, DeviceInfo, HashPool, Jwt, JwtError, KeyStore, RefreshTokenKey, RefreshTokenRepository, RotatedPair, UserGrants};
 
+/// Роль, которая есть у всех пользователей без записи в `user_roles`
+pub const BASE_ROLE: &str = "User";
 
+/// Роль с полным доступом, она же основная роль в `Claims.role`
+pub const ADMIN_ROLE: &str = "Admin";
 
-async fn public_key() -> Result<String, std::io::Error> {
-    fs::read_to_string("ed25519_public.pem").await
+/// `typ` в заголовке access токена. Access и refresh токены подписаны одним ключом
+/// с одинаковыми aud/iss, поэтому различаются только по нему
+pub const ACCESS_TOKEN_TYP: &str = "AccessToken";
+
+/// `typ` в заголовке refresh токена
+pub const REFRESH_TOKEN_TYP: &str = "RefreshToken";
+
+/// Отозванные access токены по jti
+pub const REVOKED_ACCESS: CacheNamespace = CacheNamespace::new("revoked_acc", 1);
+
+/// Время в миллисекундах, раньше которого access токены пользователя не принимаются
+pub const TOKENS_VALID_AFTER: CacheNamespace = CacheNamespace::new("tokens_valid_after", 2);
+
+/// Пара, выданная при ротации refresh токена с данным jti
+pub const REFRESH_GRACE: CacheNamespace = CacheNamespace::new("refresh_grace", 1);
+
+impl RefreshTokenKey {
+    /// 32 байта в hex. Пустой или неверный ключ - ошибка запуска: с пустым ключом digest может посчитать кто угодно
+    pub fn from_hex(key: &str) -> Result<Self, JwtError> {
+        let bytes = hex::decode(key).map_err(|e| JwtError::RefreshKey(e.to_string()))?;
+        let key = <[u8; 32]>::try_from(bytes.as_slice())
+            .map_err(|_| JwtError::RefreshKey(format!("expected 32 bytes, got {}", bytes.len())))?;
+        Ok(RefreshTokenKey(key))
+    }
+}
+
+impl std::fmt::Debug for RefreshTokenKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RefreshTokenKey(***)")
+    }
+}
+
+impl Claims {
+    pub fn has_role(&self, role: &str) -> bool {
+        self.role == role || self.roles.iter().any(|val| val == role)
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool {
+        self.scopes.iter().any(|val| val == permission)
+    }
+
+    /// Время выдачи в миллисекундах; для токенов без `iat_ms` - начало секунды `iat`
+    pub fn issued_at_ms(&self) -> i64 {
+        if self.iat_ms > 0 { self.iat_ms } else { self.iat as i64 * 1000 }
+    }
+}
+
+impl Default for UserGrants {
+    fn default() -> Self {
+        UserGrants { roles: vec![BASE_ROLE.to_owned()], scopes: Vec::new() }
+    }
 }
 
-async fn private_key() -> Result<String, std::io::Error> {
-    fs::read_to_string("ed25519_private.pem").await
+impl UserGrants {
+    pub fn primary_role(&self) -> &str {
+        if self.roles.iter().any(|role| role == ADMIN_ROLE) { ADMIN_ROLE } else { BASE_ROLE }
+    }
 }
 
 
+
 impl Jwt {
-    pub async fn create_acc_token(id: &str, role: &str) -> Result<String, JwtError> {
-        let private_key = match private_key().await.map_err(|e| JwtError::ReadingKey(e)) {
-            Ok(token) => token,
-            Err(e) => {
-                error!("Ошибка чтения refresh key: {}", e);
-                return Err(e)
-            }
-        };
+    pub async fn create_acc_token(id: &str, grants: &UserGrants, keys: Arc<KeyStore>) -> Result<String, JwtError> {
+        let ring = keys.current();
+        let (kid, private_key) = ring.signing_key()?;
 
-        let now = Utc::now();
+        let now = keys.clock.now();
         let claims = Claims {
-            sub: format!("{}", id),
+            sub: id.to_owned(),
             iss: "server".to_owned(),
             aud: "all".to_owned(),
             iat: now.timestamp() as usize,
-            exp: (now+Duration::minutes(15)).timestamp() as usize,
-            role: role.to_owned(),
+            iat_ms: now.timestamp_millis(),
+            exp: (now+Duration::seconds(keys.settings.access_ttl)).timestamp() as usize,
+            role: grants.primary_role().to_owned(),
+            roles: grants.roles.clone(),
+            scopes: grants.scopes.clone(),
             jti: Uuid::new_v4().to_string(),
         };
 
         info!("Access token обновлен!");
         let mut header = Header::new(Algorithm::EdDSA);
-        header.typ = Some("AccessToken".to_owned());
-        encode(&header, &claims, &EncodingKey::from_ed_pem(private_key.as_bytes())?).map_err(JwtError::from)
+        header.typ = Some(ACCESS_TOKEN_TYP.to_owned());
+        header.kid = Some(kid.to_owned());
+        encode(&header, &claims, private_key).map_err(JwtError::from)
     }
 
 
@@ -52,11 +108,12 @@ impl Jwt {
 
 
 
-    pub async fn verify_acc_token(token: &str) -> Result<Claims, JwtError> {
-        let public_key = match public_key().await.map_err(|e| JwtError::ReadingKey(e)) {
-            Ok(token) => token,
+    pub async fn verify_acc_token(token: &str, keys: Arc<KeyStore>) -> Result<Claims, JwtError> {
+        let ring = keys.current();
+        let public_key = match ring.decoding_key(token, &*keys.clock) {
+            Ok(key) => key,
             Err(e) => {
-                error!("Ошибка чтения access key: {}", e);
+                error!("Не удалось выбрать ключ проверки: {e}");
                 return Err(e)
             }
         };
@@ -68,9 +125,13 @@ impl Jwt {
         val.reject_tokens_expiring_in_less_than = 10;
         val.validate_aud = true;
 
-        let data = decode::<Claims>(token, &DecodingKey::from_ed_pem(public_key.as_bytes())?, &val).map_err(JwtError::from);
+        let data = decode::<Claims>(token, public_key, &val).map_err(JwtError::from);
         match data {
-            Ok(claims) => return Ok(claims.claims),
+            Ok(claims) if claims.header.typ.as_deref() == Some(ACCESS_TOKEN_TYP) => return Ok(claims.claims),
+            Ok(claims) => {
+                error!("Вместо access token передан токен типа {:?}", claims.header.typ);
+                return Err(JwtError::WrongType(claims.header.typ))
+            },
             Err(e) => {
                 error!("Ошибка проверки access token: {e}");
                 return Err(e)
@@ -83,29 +144,28 @@ impl Jwt {
 
 
 
-    pub async fn create_ref_token(id: &str, role: &str) -> Result<String, JwtError> {
-        let private_key = match private_key().await.map_err(|e| JwtError::ReadingKey(e)) {
-            Ok(token) => token,
-            Err(e) => {
-                error!("Ошибка чтения refresh key: {}", e);
-                return Err(e)
-            }
-        };
+    pub async fn create_ref_token(id: &str, role: &str, keys: Arc<KeyStore>) -> Result<String, JwtError> {
+        let ring = keys.current();
+        let (kid, private_key) = ring.signing_key()?;
 
-        let now = Utc::now();
+        let now = keys.clock.now();
         let claims = Claims {
-            sub: format!("{}", id),
+            sub: id.to_owned(),
             iss: "server".to_owned(),
             aud: "all".to_owned(),
             iat: now.timestamp() as usize,
-            exp: (now+Duration::days(30)).timestamp() as usize,
+            iat_ms: now.timestamp_millis(),
+            exp: (now+Duration::days(keys.settings.refresh_ttl_days)).timestamp() as usize,
             role: role.to_owned(),
+            roles: Vec::new(),
+            scopes: Vec::new(),
             jti: Uuid::new_v4().to_string(),
         };
 
         let mut header = Header::new(Algorithm::EdDSA);
-        header.typ = Some("RefreshToken".to_owned());
-        encode(&header, &claims, &EncodingKey::from_ed_pem(private_key.as_bytes())?).map_err(JwtError::from)
+        header.typ = Some(REFRESH_TOKEN_TYP.to_owned());
+        header.kid = Some(kid.to_owned());
+        encode(&header, &claims, private_key).map_err(JwtError::from)
     }
 
 
@@ -113,11 +173,20 @@ impl Jwt {
 
 
 
-    pub async fn verify_ref_token(token: &str, pool: Arc<PgPool>, search_in_db: bool) -> Result<Claims, JwtError> {
-        let public_key = match public_key().await.map_err(|e| JwtError::ReadingKey(e)) {
-            Ok(token) => token,
+    /// HMAC-SHA256 от refresh токена в hex, под ним токен хранится и ищется в БД.
+    /// Токен - подписанный JWT с большой энтропией, поэтому медленный Argon2 здесь не нужен
+    pub fn token_digest(token: &str, key: &RefreshTokenKey) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key.0).expect("HMAC принимает ключ любой длины");
+        mac.update(token.as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    pub async fn verify_ref_token(token: &str, keys: Arc<KeyStore>, tokens: Arc<dyn RefreshTokenRepository>, hasher: Arc<HashPool>, search_in_db: bool) -> Result<Claims, JwtError> {
+        let ring = keys.current();
+        let public_key = match ring.decoding_key(token, &*keys.clock) {
+            Ok(key) => key,
             Err(e) => {
-                error!("Ошибка чтения access key: {}", e);
+                error!("Не удалось выбрать ключ проверки: {e}");
                 return Err(e)
             }
         };
@@ -129,12 +198,18 @@ impl Jwt {
         val.reject_tokens_expiring_in_less_than = 20;
         val.validate_aud = true;
 
-        let data = decode::<Claims>(token, &DecodingKey::from_ed_pem(public_key.as_bytes())?, &val).map_err(JwtError::from);
+        let data = decode::<Claims>(token, public_key, &val).map_err(JwtError::from);
         match data {
+            Ok(claims) if claims.header.typ.as_deref() != Some(REFRESH_TOKEN_TYP) => {
+                error!("Вместо refresh token передан токен типа {:?}", claims.header.typ);
+                return Err(JwtError::WrongType(claims.header.typ))
+            },
             Ok(claims) => {
-                if search_in_db == true {
-                    match DataBase::verify_ref_token(&claims.claims.sub, token, &claims.claims.jti, pool).await {
+                if search_in_db {
+                    match DataBase::verify_ref_token(&claims.claims.sub, token, &claims.claims.jti, tokens, hasher, &keys.refresh_key).await {
                         Ok(_) => (),
+                        Err(DataBaseError::SomeArgonError(ArgonError::Busy { retry_after })) => {
+                            return Err(JwtError::Overloaded(retry_after))},
                         Err(_) => {
                             error!("Refresh токен не найден в базе данных либо не верен");
                             return Err(JwtError::DataBaseNotFound)},
@@ -158,6 +233,56 @@ impl Jwt {
         }
     }
 
+    /// Заносит jti access токена в denylist до окончания его срока действия
+    pub async fn revoke_acc_token(claims: &Claims, keys: Arc<KeyStore>, cache: Arc<dyn Cache>) -> Result<(), JwtError> {
+        let ttl = (claims.exp as i64 - keys.clock.now().timestamp()).max(1) as u64;
+        cache.set(&REVOKED_ACCESS.key(&claims.jti), &true, ttl).await
+            .map_err(|e| JwtError::Revocation(e.to_string()))
+    }
+
+    /// Все access токены пользователя, выданные раньше текущего момента, перестают приниматься.
+    /// Хранится не дольше жизни access токена, refresh токены при этом удаляются из БД отдельно
+    pub async fn revoke_user_tokens(user_id: &str, keys: Arc<KeyStore>, cache: Arc<dyn Cache>) -> Result<(), JwtError> {
+        let now = keys.clock.now().timestamp_millis();
+        cache.set(&TOKENS_VALID_AFTER.key(user_id), &now, keys.settings.access_ttl as u64).await
+            .map_err(|e| JwtError::Revocation(e.to_string()))
+    }
+
+    /// Проверка denylist и watermark. Если Redis недоступен, токен считается отозванным,
+    /// пока не включён `jwt.revocation_fail_open`
+    pub async fn is_acc_token_revoked(claims: &Claims, keys: Arc<KeyStore>, cache: Arc<dyn Cache>) -> bool {
+        let cache_keys = [REVOKED_ACCESS.key(&claims.jti), TOKENS_VALID_AFTER.key(&claims.sub)];
+        let values = match cache.mget_str(&cache_keys).await {
+            Ok(values) => values,
+            Err(e) => {
+                error!("Не удалось проверить отзыв access токена: {e}");
+                return !keys.settings.revocation_fail_open
+            }
+        };
+
+        if values.first().is_some_and(|val| val.is_some()) {
+            return true
+        }
+        match values.get(1).and_then(|val| val.as_ref()).and_then(|val| val.parse::<i64>().ok()) {
+            // токен, выданный в момент отзыва (вход после смены пароля, refresh после смены ролей), действует
+            Some(valid_after) => claims.issued_at_ms() < valid_after,
+            None => false,
+        }
+    }
+
+    /// Access токен из заголовка `Authorization: Bearer <jwt>`, пустая строка если его нет
+    pub fn get_bearer_token(headers: &HeaderMap) -> String {
+        let value = match headers.get(header::AUTHORIZATION).and_then(|val| val.to_str().ok()) {
+            Some(val) => val.trim(),
+            None => return String::new()
+        };
+
+        match value.split_once(' ') {
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("bearer") => return token.trim().to_owned(),
+            _ => return String::new()
+        }
+    }
+
     pub async fn get_access_token(jar: &CookieJar) -> String {
         match jar.get("AccessToken") {
             Some(val) => {
@@ -173,4 +298,74 @@ impl Jwt {
     }
 
 
-}
\ No newline at end of file
+}
+
+async fn grace_pair(cache: Arc<dyn Cache>, jti: &str) -> Option<RotatedPair> {
+    cache.get(&REFRESH_GRACE.key(jti)).await.ok().flatten()
+}
+
+impl Jwt {
+    /// Ротация refresh токена: выдаёт новую пару, повторно отдаёт пару параллельным запросам
+    /// в течение `jwt.refresh_grace` секунд и отзывает всю семью при повторном использовании токена
+    pub async fn refresh_pair(refresh_token: &str, device: &DeviceInfo, state: &AppState) -> Result<RotatedPair, JwtError> {
+        let AppState { users, tokens, cache, hasher, keys, config, .. } = state.clone();
+        let verified = Jwt::verify_ref_token(refresh_token, Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher), true).await;
+        if let Err(JwtError::Overloaded(retry_after)) = verified {
+            return Err(JwtError::Overloaded(retry_after))
+        }
+        if let Ok(claims) = verified {
+            if let Some(pair) = grace_pair(Arc::clone(&cache), &claims.jti).await {
+                info!("Refresh токен {} уже ротирован параллельным запросом, отдаём ту же пару", claims.jti);
+                return Ok(pair)
+            }
+
+            let family_id = match tokens.rotate_ref_token(&claims.sub, &claims.jti).await {
+                Ok(family_id) => family_id,
+                Err(DataBaseError::TokenReuse { family_id, rotated_at }) => {
+                    let grace = Duration::seconds(config.jwt.refresh_grace as i64);
+                    if rotated_at.is_some_and(|rotated_at| rotated_at + grace > keys.clock.now()) {
+                        // параллельный запрос ротировал токен, но ещё не успел сохранить пару
+                        for _ in 0..10 {
+                            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+                            if let Some(pair) = grace_pair(Arc::clone(&cache), &claims.jti).await {
+                                return Ok(pair)
+                            }
+                        }
+                        return Err(JwtError::Refresh("refresh token was rotated concurrently".to_owned()))
+                    }
+
+                    // токен уже ротирован: вероятно украден, отзываем всю семью
+                    let details = serde_json::json!({"jti": claims.jti, "family_id": family_id}).to_string();
+                    if let Err(e) = users.save_security_event(&claims.sub, "refresh_token_reuse", &details).await {
+                        error!("Не удалось записать событие refresh_token_reuse для {}: {e}", claims.sub);
+                    }
+                    if let Err(e) = tokens.revoke_ref_family(&claims.sub, &family_id).await {
+                        error!("Не удалось отозвать семью {family_id} после повторного использования токена: {e}");
+                        return Err(JwtError::Refresh(format!("failed to revoke token family: {e}")))
+                    }
+                    return Err(JwtError::RefreshReuse)
+                },
+                Err(e) => return Err(JwtError::Refresh(e.to_string())),
+            };
+
+            // роли могли измениться с прошлой выдачи, поэтому читаем их заново
+            let grants = users.get_user_grants(&claims.sub).await
+                .map_err(|e| JwtError::Refresh(e.to_string()))?;
+            let refresh_token = Jwt::create_ref_token(&claims.sub, grants.primary_role(), Arc::clone(&keys)).await?;
+            DataBase::save_ref_token(&refresh_token, &family_id, device, Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher)).await
+                .map_err(|e| {
+                    error!("Не удалось сохранить новый refresh токен семьи {family_id}: {e}");
+                    JwtError::Refresh(e.to_string())
+                })?;
+
+            Jwt::verify_ref_token(&refresh_token, Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher), true).await?;
+            let access_token = Jwt::create_acc_token(&claims.sub, &grants, Arc::clone(&keys)).await?;
+
+            let pair = RotatedPair { access_token, refresh_token };
+            cache.set(&REFRESH_GRACE.key(&claims.jti), &pair, config.jwt.refresh_grace).await.unwrap_or(());
+            Ok(pair)
+        } else {
+            Err(JwtError::Refresh("invalid refresh token".to_owned()))
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use base64::{engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD}, Engine};
+use chrono::{Duration, Utc};
+use jsonwebtoken::{decode_header, DecodingKey, EncodingKey,
+    jwk::{AlgorithmParameters, CommonParameters, EllipticCurve, Jwk, JwkSet, KeyAlgorithm,
+        OctetKeyPairParameters, OctetKeyPairType, PublicKeyUse}};
+use std::{io::ErrorKind, sync::{Arc, Mutex, RwLock}, time::{Duration as StdDuration, SystemTime}};
+use tokio::fs;
+use log::{error, info, warn};
+
+use crate::models::{Clock, JwtConfig, JwtError, KeyRing, KeyRingFile, KeyStore, RefreshTokenKey, SigningKey};
+
+/// kid для ключа из `ed25519_*.pem`, им же проверяются старые токены без `kid`
+pub const DEFAULT_KID: &str = "default";
+
+/// Префикс SubjectPublicKeyInfo (DER) для Ed25519, за ним идут 32 байта ключа
+const ED25519_SPKI_PREFIX: [u8; 12] = [0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00];
+
+fn pem_to_raw_public(pem: &str) -> Result<Vec<u8>, JwtError> {
+    let body: String = pem.lines()
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    let der = STANDARD.decode(body.trim())
+        .map_err(|e| JwtError::KeyRing(format!("public key is not valid PEM: {e}")))?;
+
+    if der.len() != ED25519_SPKI_PREFIX.len() + 32 || der[..ED25519_SPKI_PREFIX.len()] != ED25519_SPKI_PREFIX {
+        return Err(JwtError::KeyRing("public key is not an Ed25519 key".to_owned()))
+    }
+    Ok(der[ED25519_SPKI_PREFIX.len()..].to_vec())
+}
+
+async fn load_key(kid: &str, private: Option<&str>, public: &str, retired_at: Option<chrono::DateTime<Utc>>) -> Result<SigningKey, JwtError> {
+    let public_pem = fs::read_to_string(public).await?;
+    let encoding = match private {
+        Some(path) => {
+            let private_pem = fs::read_to_string(path).await?;
+            Some(EncodingKey::from_ed_pem(private_pem.as_bytes())?)
+        },
+        None => None,
+    };
+
+    Ok(SigningKey {
+        kid: kid.to_owned(),
+        encoding,
+        decoding: DecodingKey::from_ed_pem(public_pem.as_bytes())?,
+        public_raw: pem_to_raw_public(&public_pem)?,
+        retired_at,
+    })
+}
+
+impl KeyRing {
+    /// Читает `keyring.json` (путь задаётся в `jwt.keyring`).
+    /// Без файла используется одна пара `ed25519_private.pem`/`ed25519_public.pem` с kid "default"
+    pub async fn load(path: &str) -> Result<KeyRing, JwtError> {
+        let path = path.to_owned();
+
+        let file = match fs::read_to_string(&path).await {
+            Ok(data) => match serde_json::from_str::<KeyRingFile>(&data) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Ошибка парсинга {path}: {e}");
+                    return Err(JwtError::KeyRing(format!("{path}: {e}")))
+                }
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let key = load_key(DEFAULT_KID, Some("ed25519_private.pem"), "ed25519_public.pem", None).await?;
+                return Ok(KeyRing {
+                    active: DEFAULT_KID.to_owned(),
+                    grace: Duration::days(30),
+                    keys: vec![key],
+                    sources: vec![path, "ed25519_private.pem".to_owned(), "ed25519_public.pem".to_owned()],
+                })
+            },
+            Err(e) => return Err(JwtError::ReadingKey(e)),
+        };
+
+        let mut keys: Vec<SigningKey> = Vec::new();
+        let mut sources = vec![path];
+        for entry in &file.keys {
+            if keys.iter().any(|key| key.kid == entry.kid) {
+                return Err(JwtError::KeyRing(format!("duplicate kid \"{}\"", entry.kid)))
+            }
+            keys.push(load_key(&entry.kid, entry.private.as_deref(), &entry.public, entry.retired_at).await?);
+            sources.extend(entry.private.iter().cloned());
+            sources.push(entry.public.clone());
+        }
+
+        match keys.iter().find(|key| key.kid == file.active) {
+            Some(key) if key.encoding.is_none() => {
+                return Err(JwtError::KeyRing(format!("active key \"{}\" has no private key", file.active)))
+            },
+            Some(key) if key.retired_at.is_some() => {
+                return Err(JwtError::KeyRing(format!("active key \"{}\" is retired", file.active)))
+            },
+            Some(_) => (),
+            None => return Err(JwtError::KeyRing(format!("active key \"{}\" not found", file.active))),
+        }
+
+        Ok(KeyRing {
+            active: file.active,
+            grace: Duration::days(file.grace_days),
+            keys,
+            sources,
+        })
+    }
+
+    /// Ключ, которым подписываются новые токены
+    pub fn signing_key(&self) -> Result<(&str, &EncodingKey), JwtError> {
+        self.keys.iter()
+            .find(|key| key.kid == self.active)
+            .and_then(|key| key.encoding.as_ref().map(|encoding| (key.kid.as_str(), encoding)))
+            .ok_or(JwtError::UnknownKid(self.active.clone()))
+    }
+
+    /// Ключ ещё принимается, если он не выведен из оборота или grace период не истёк по часам `KeyStore`
+    fn is_usable(&self, key: &SigningKey, clock: &dyn Clock) -> bool {
+        match key.retired_at {
+            Some(retired_at) => retired_at + self.grace > clock.now(),
+            None => true,
+        }
+    }
+
+    /// Выбирает ключ проверки по `kid` из заголовка токена
+    pub fn decoding_key(&self, token: &str, clock: &dyn Clock) -> Result<&DecodingKey, JwtError> {
+        let header = decode_header(token)?;
+        let kid = header.kid.unwrap_or(DEFAULT_KID.to_owned());
+
+        match self.keys.iter().find(|key| key.kid == kid) {
+            Some(key) if self.is_usable(key, clock) => Ok(&key.decoding),
+            Some(_) => {
+                warn!("Токен подписан ключом {kid}, grace период которого истёк");
+                Err(JwtError::UnknownKid(kid))
+            },
+            None => Err(JwtError::UnknownKid(kid)),
+        }
+    }
+
+    /// Публичные ключи, которые ещё принимаются, в формате JWKS
+    pub fn jwks(&self, clock: &dyn Clock) -> JwkSet {
+        let keys = self.keys.iter()
+            .filter(|key| self.is_usable(key, clock))
+            .map(|key| Jwk {
+                common: CommonParameters {
+                    public_key_use: Some(PublicKeyUse::Signature),
+                    key_algorithm: Some(KeyAlgorithm::EdDSA),
+                    key_id: Some(key.kid.clone()),
+                    ..Default::default()
+                },
+                algorithm: AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                    key_type: OctetKeyPairType::OctetKeyPair,
+                    curve: EllipticCurve::Ed25519,
+                    x: URL_SAFE_NO_PAD.encode(&key.public_raw),
+                }),
+            })
+            .collect();
+
+        JwkSet { keys }
+    }
+}
+
+async fn modified_times(sources: &[String]) -> Vec<(String, Option<SystemTime>)> {
+    let mut times = Vec::with_capacity(sources.len());
+    for path in sources {
+        let modified = fs::metadata(path).await.and_then(|meta| meta.modified()).ok();
+        times.push((path.clone(), modified));
+    }
+    times
+}
+
+impl KeyStore {
+    /// Загружает ключи при старте, ошибка здесь должна останавливать запуск сервера
+    pub async fn load(settings: &JwtConfig, clock: Arc<dyn Clock>) -> Result<KeyStore, JwtError> {
+        let refresh_key = RefreshTokenKey::from_hex(&settings.refresh_token_key)?;
+        let ring = KeyRing::load(&settings.keyring).await?;
+        let modified = modified_times(&ring.sources).await;
+        info!("Загружено ключей JWT: {}, активный: {}", ring.keys.len(), ring.active);
+
+        Ok(KeyStore {
+            ring: RwLock::new(Arc::new(ring)),
+            modified: Mutex::new(modified),
+            settings: settings.clone(),
+            refresh_key,
+            clock,
+        })
+    }
+
+    pub fn current(&self) -> Arc<KeyRing> {
+        match self.ring.read() {
+            Ok(ring) => Arc::clone(&ring),
+            Err(poisoned) => Arc::clone(&poisoned.into_inner()),
+        }
+    }
+
+    /// Перечитывает ключи, при ошибке остаются старые
+    pub async fn reload(&self) -> Result<(), JwtError> {
+        let ring = match KeyRing::load(&self.settings.keyring).await {
+            Ok(ring) => ring,
+            Err(e) => {
+                error!("Не удалось перезагрузить ключи, используются старые: {e}");
+                return Err(e)
+            }
+        };
+        let modified = modified_times(&ring.sources).await;
+        info!("Ключи JWT перезагружены, активный: {}", ring.active);
+
+        match self.ring.write() {
+            Ok(mut current) => *current = Arc::new(ring),
+            Err(poisoned) => *poisoned.into_inner() = Arc::new(ring),
+        }
+        match self.modified.lock() {
+            Ok(mut current) => *current = modified,
+            Err(poisoned) => *poisoned.into_inner() = modified,
+        }
+        Ok(())
+    }
+
+    async fn files_changed(&self) -> bool {
+        let known = match self.modified.lock() {
+            Ok(known) => known.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        };
+        let paths: Vec<String> = known.iter().map(|(path, _)| path.clone()).collect();
+        modified_times(&paths).await != known
+    }
+
+    /// Перезагрузка ключей по SIGHUP и при изменении файлов (проверка раз в `interval`)
+    pub fn watch(self: Arc<Self>, interval: StdDuration) {
+        #[cfg(unix)]
+        {
+            let store = Arc::clone(&self);
+            tokio::spawn(async move {
+                use tokio::signal::unix::{signal, SignalKind};
+                let mut hangup = match signal(SignalKind::hangup()) {
+                    Ok(hangup) => hangup,
+                    Err(e) => {
+                        error!("Не удалось подписаться на SIGHUP: {e}");
+                        return
+                    }
+                };
+                while hangup.recv().await.is_some() {
+                    info!("Получен SIGHUP, перезагрузка ключей");
+                    let _ = store.reload().await;
+                }
+            });
+        }
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                if self.files_changed().await {
+                    info!("Файлы ключей изменились, перезагрузка");
+                    let _ = self.reload().await;
+                }
+            }
+        });
+    }
+}
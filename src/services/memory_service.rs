@@ -0,0 +1,390 @@
+use std::{cmp::Reverse, collections::HashMap, sync::{Arc, Mutex}};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+
+use crate::models::{Cache, Clock, CustomRedisError, DataBaseError, MemoryCache, MemoryRefreshTokenRepository, MemoryUserRepository, OwnProfile, PasswordHash, PasswordHashGroup, RefreshTokenRecord, RefreshTokenRepository, SecurityEvent, Session, SortOrder, User, UserGrants, UserListQuery, UserPatch, UserRepository, UserSort};
+use crate::services::jwt_service::{ADMIN_ROLE, BASE_ROLE};
+
+impl Default for MemoryUserRepository {
+    /// Роли и права как после миграций: у Admin все права, у User никаких
+    fn default() -> Self {
+        let admin = ["roles:write", "sessions:write", "users:read", "users:write"].map(str::to_owned).to_vec();
+        MemoryUserRepository {
+            users: Mutex::new(Vec::new()),
+            user_roles: Mutex::new(HashMap::new()),
+            role_permissions: HashMap::from([(BASE_ROLE.to_owned(), Vec::new()), (ADMIN_ROLE.to_owned(), admin)]),
+            events: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl MemoryUserRepository {
+    pub fn new() -> Self {
+        MemoryUserRepository::default()
+    }
+
+    /// Сохранённые security events, для проверок в тестах
+    pub fn events(&self) -> Vec<SecurityEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn known_role(&self, role: &str) -> Result<(), DataBaseError> {
+        match self.role_permissions.contains_key(role) {
+            true => Ok(()),
+            false => Err(DataBaseError::UnknownRole(role.to_owned())),
+        }
+    }
+}
+
+fn parse_id(id: &str) -> Result<i64, DataBaseError> {
+    id.parse::<i64>().map_err(|_| DataBaseError::NotFound)
+}
+
+/// Значение колонки сортировки `/all` и id для равных значений, строки сравниваются побайтово
+fn sort_key(user: &User, sort: UserSort) -> (String, i64) {
+    match sort {
+        UserSort::Id => (String::new(), user.id),
+        UserSort::Nickname => (user.nickname.clone(), user.id),
+        UserSort::Name => (user.name.clone(), user.id),
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryUserRepository {
+    async fn create_user(&self, nickname: &str, name: &str, password_hash: &str) -> Result<User, DataBaseError> {
+        let mut users = self.users.lock().unwrap();
+        if users.iter().any(|user| user.nickname == nickname) {
+            return Err(DataBaseError::Conflict("nickname"))
+        }
+
+        let id = users.last().map(|user| user.id + 1).unwrap_or(1);
+        let user = User { id, nickname: nickname.to_owned(), name: name.to_owned(), password: PasswordHash(password_hash.to_owned()) };
+        users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<(), DataBaseError> {
+        self.users.lock().unwrap().retain(|user| user.id != id);
+        self.user_roles.lock().unwrap().remove(&id);
+        Ok(())
+    }
+
+    async fn get_user_credentials(&self, nickname: &str) -> Result<User, DataBaseError> {
+        let users = self.users.lock().unwrap();
+        users.iter().find(|user| user.nickname == nickname).cloned().ok_or(DataBaseError::NotFound)
+    }
+
+    async fn get_user(&self, nickname: &str) -> Result<OwnProfile, DataBaseError> {
+        self.get_user_credentials(nickname).await.map(|user| OwnProfile::from(&user))
+    }
+
+    async fn get_user_by_id(&self, id: &str) -> Result<OwnProfile, DataBaseError> {
+        let id = parse_id(id)?;
+        let users = self.users.lock().unwrap();
+        users.iter().find(|user| user.id == id).map(OwnProfile::from).ok_or(DataBaseError::NotFound)
+    }
+
+    async fn find_users(&self, list: &UserListQuery, limit: i64) -> Result<Vec<OwnProfile>, DataBaseError> {
+        let prefix = list.q.as_deref().map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+        let users = self.users.lock().unwrap();
+
+        let after = match list.after {
+            Some(after) => match users.iter().find(|user| user.id == after) {
+                Some(user) => Some(sort_key(user, list.sort)),
+                // как в SQL: сравнение с несуществующей строкой не пропускает ничего
+                None => return Ok(Vec::new()),
+            },
+            None => None,
+        };
+
+        let mut found: Vec<&User> = users.iter()
+            .filter(|user| match &prefix {
+                Some(prefix) => user.nickname.to_lowercase().starts_with(prefix) || user.name.to_lowercase().starts_with(prefix),
+                None => true,
+            })
+            .filter(|user| match (&after, list.order) {
+                (Some(after), SortOrder::Asc) => sort_key(user, list.sort) > *after,
+                (Some(after), SortOrder::Desc) => sort_key(user, list.sort) < *after,
+                (None, _) => true,
+            })
+            .collect();
+        found.sort_by_key(|user| sort_key(user, list.sort));
+        if list.order == SortOrder::Desc {
+            found.reverse();
+        }
+
+        Ok(found.into_iter().take(limit.max(0) as usize).map(OwnProfile::from).collect())
+    }
+
+    async fn update_user(&self, id: &str, patch: &UserPatch, password_hash: Option<&str>) -> Result<User, DataBaseError> {
+        let id = parse_id(id)?;
+        let mut users = self.users.lock().unwrap();
+        if let Some(nickname) = &patch.nickname {
+            if users.iter().any(|user| user.id != id && &user.nickname == nickname) {
+                return Err(DataBaseError::Conflict("nickname"))
+            }
+        }
+
+        let user = users.iter_mut().find(|user| user.id == id).ok_or(DataBaseError::NotFound)?;
+        if let Some(nickname) = &patch.nickname {
+            user.nickname = nickname.clone();
+        }
+        if let Some(name) = &patch.name {
+            user.name = name.trim().to_owned();
+        }
+        if let Some(password_hash) = password_hash {
+            user.password = PasswordHash(password_hash.to_owned());
+        }
+        Ok(user.clone())
+    }
+
+    async fn get_user_grants(&self, user_id: &str) -> Result<UserGrants, DataBaseError> {
+        let id = user_id.parse::<i64>().unwrap_or(-1);
+        let mut roles = self.user_roles.lock().unwrap().get(&id).cloned().unwrap_or_default();
+        roles.sort();
+        if !roles.iter().any(|role| role == BASE_ROLE) {
+            roles.insert(0, BASE_ROLE.to_owned());
+        }
+
+        let mut scopes: Vec<String> = roles.iter()
+            .filter_map(|role| self.role_permissions.get(role))
+            .flatten()
+            .cloned()
+            .collect();
+        scopes.sort();
+        scopes.dedup();
+        Ok(UserGrants { roles, scopes })
+    }
+
+    async fn grant_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError> {
+        self.known_role(role)?;
+        let id = user_id.parse::<i64>().unwrap_or(-1);
+        if !self.users.lock().unwrap().iter().any(|user| user.id == id) {
+            return Err(DataBaseError::NotFound)
+        }
+
+        let mut user_roles = self.user_roles.lock().unwrap();
+        let roles = user_roles.entry(id).or_default();
+        if !roles.iter().any(|val| val == role) {
+            roles.push(role.to_owned());
+        }
+        Ok(())
+    }
+
+    async fn revoke_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError> {
+        self.known_role(role)?;
+        let id = user_id.parse::<i64>().unwrap_or(-1);
+        if let Some(roles) = self.user_roles.lock().unwrap().get_mut(&id) {
+            roles.retain(|val| val != role);
+        }
+        Ok(())
+    }
+
+    async fn save_security_event(&self, user_id: &str, kind: &str, details: &str) -> Result<(), DataBaseError> {
+        self.events.lock().unwrap().push(SecurityEvent { user_id: user_id.to_owned(), kind: kind.to_owned(), details: details.to_owned() });
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), DataBaseError> {
+        Ok(())
+    }
+
+    async fn replace_password_hash(&self, user_id: i64, old_hash: &PasswordHash, new_hash: &str) -> Result<bool, DataBaseError> {
+        let mut users = self.users.lock().unwrap();
+        match users.iter_mut().find(|user| user.id == user_id && user.password == *old_hash) {
+            Some(user) => {
+                user.password = PasswordHash(new_hash.to_owned());
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+
+    /// Группирует так же, как запрос в PgUserRepository
+    async fn password_hash_groups(&self) -> Result<Vec<PasswordHashGroup>, DataBaseError> {
+        let users = self.users.lock().unwrap();
+        let mut groups: Vec<(Vec<String>, PasswordHashGroup)> = Vec::new();
+        for user in users.iter() {
+            let parts: Vec<&str> = user.password.as_str().split('$').collect();
+            let part = |n: usize| parts.get(n).copied().unwrap_or("").to_owned();
+            let key = vec![part(1), part(2), part(3), part(5).len().to_string()];
+            match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+                Some((_, group)) => group.users += 1,
+                None => groups.push((key, PasswordHashGroup { sample: user.password.clone(), users: 1 })),
+            }
+        }
+        Ok(groups.into_iter().map(|(_, group)| group).collect())
+    }
+}
+
+impl MemoryRefreshTokenRepository {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        MemoryRefreshTokenRepository { tokens: Mutex::new(Vec::new()), clock }
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepository for MemoryRefreshTokenRepository {
+    async fn save_ref_token(&self, token: &RefreshTokenRecord) -> Result<(), DataBaseError> {
+        let mut tokens = self.tokens.lock().unwrap();
+        if tokens.iter().any(|val| val.jti == token.jti) {
+            return Err(DataBaseError::SaveError)
+        }
+        tokens.push(token.clone());
+        Ok(())
+    }
+
+    async fn get_token_hash(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        let tokens = self.tokens.lock().unwrap();
+        tokens.iter()
+            .find(|token| token.user_id == user_id && token.jti == jti)
+            .map(|token| token.token_hash.clone())
+            .ok_or(DataBaseError::NotFound)
+    }
+
+    async fn find_token_by_digest(&self, digest: &str) -> Result<(i64, String), DataBaseError> {
+        let tokens = self.tokens.lock().unwrap();
+        tokens.iter()
+            .find(|token| token.token_hash == digest)
+            .map(|token| (token.user_id, token.jti.clone()))
+            .ok_or(DataBaseError::NotFound)
+    }
+
+    async fn rotate_ref_token(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        let mut tokens = self.tokens.lock().unwrap();
+        let token = tokens.iter_mut()
+            .find(|token| token.user_id == user_id && token.jti == jti)
+            .ok_or(DataBaseError::NotFound)?;
+
+        match token.rotated_at {
+            Some(rotated_at) => Err(DataBaseError::TokenReuse { family_id: token.family_id.clone(), rotated_at: Some(rotated_at) }),
+            None => {
+                token.rotated_at = Some(self.clock.now());
+                Ok(token.family_id.clone())
+            }
+        }
+    }
+
+    async fn revoke_ref_family(&self, user_id: &str, family_id: &str) -> Result<(), DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        self.tokens.lock().unwrap().retain(|token| !(token.user_id == user_id && token.family_id == family_id));
+        Ok(())
+    }
+
+    async fn del_ref_token(&self, user_id: &str, jti: &str) -> Result<(), DataBaseError> {
+        let id = user_id.parse::<i64>().unwrap_or(-1);
+        let family_id = self.tokens.lock().unwrap().iter()
+            .find(|token| token.user_id == id && token.jti == jti)
+            .map(|token| token.family_id.clone());
+
+        match family_id {
+            Some(family_id) => self.revoke_ref_family(user_id, &family_id).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn del_all_ref_tokens(&self, user_id: &str) -> Result<(), DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        self.tokens.lock().unwrap().retain(|token| token.user_id != user_id);
+        Ok(())
+    }
+
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<Session>, DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        let now = self.clock.now();
+        let tokens = self.tokens.lock().unwrap();
+
+        let mut sessions: Vec<Session> = tokens.iter()
+            .filter(|token| token.user_id == user_id && token.rotated_at.is_none() && token.expires_at > now)
+            .map(|token| Session {
+                jti: token.jti.clone(),
+                started_at: tokens.iter()
+                    .filter(|val| val.family_id == token.family_id)
+                    .map(|val| val.created_at)
+                    .min()
+                    .unwrap_or(token.created_at),
+                last_used_at: token.last_used_at,
+                expires_at: token.expires_at,
+                user_agent: token.device.user_agent.clone(),
+                ip: token.device.ip.clone(),
+            })
+            .collect();
+        sessions.sort_by_key(|session| Reverse(session.last_used_at));
+        Ok(sessions)
+    }
+
+    async fn purge_expired_tokens(&self) -> Result<u64, DataBaseError> {
+        let now = self.clock.now();
+        let mut tokens = self.tokens.lock().unwrap();
+        let before = tokens.len();
+        tokens.retain(|token| token.expires_at > now);
+        Ok((before - tokens.len()) as u64)
+    }
+}
+
+impl MemoryCache {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        MemoryCache { entries: Mutex::new(HashMap::new()), clock }
+    }
+
+    pub(crate) fn live_value(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((value, expires_at)) if *expires_at > self.clock.now() => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            },
+            None => None,
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for MemoryCache {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError> {
+        self.live_value(key).ok_or(CustomRedisError::NoneError)
+    }
+
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        let expires_at = self.clock.now() + Duration::seconds(ttl as i64);
+        self.entries.lock().unwrap().insert(key.to_owned(), (value.to_owned(), expires_at));
+        Ok(())
+    }
+
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        Ok(keys.iter().map(|key| self.live_value(key)).collect())
+    }
+
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        for (key, value) in items {
+            self.set_str(key, value, ttl).await?;
+        }
+        Ok(())
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    /// Как INCR в Redis: TTL ключа сохраняется, отсутствующий ключ создаётся без срока
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError> {
+        let current = self.live_value(key);
+        let value = match current.as_deref().map(str::parse::<i64>) {
+            Some(Ok(value)) => value + 1,
+            Some(Err(_)) => return Err(CustomRedisError::SomeError),
+            None => 1,
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        let expires_at = match entries.get(key) {
+            Some((_, expires_at)) => *expires_at,
+            None => DateTime::<Utc>::MAX_UTC,
+        };
+        entries.insert(key.to_owned(), (value.to_string(), expires_at));
+        Ok(value)
+    }
+}
@@ -3,4 +3,15 @@ pub mod database_service;
 pub mod argon2_service;
 pub mod time_service;
 pub mod redis_service;
-pub mod aesgcm_service;
\ No newline at end of file
+pub mod aesgcm_service;
+pub mod validation_service;
+pub mod keyring_service;
+pub mod user_service;
+pub mod error_service;
+pub mod config_service;
+pub mod repository_service;
+pub mod memory_service;
+pub mod flight_service;
+pub mod event_service;
+pub mod breaker_service;
+pub mod hash_pool_service;
@@ -1,21 +1,32 @@
 use deadpool_redis::redis::AsyncCommands;
-use deadpool_redis::{Config as RedisConfig, Pool, redis::RedisError};
-use std::{result::Result, sync::Arc};
+use deadpool_redis::{Config as PoolConfig, Pool, Timeouts, redis::RedisError};
+use std::{fmt::Display, result::Result, sync::Arc, time::Duration};
+use async_trait::async_trait;
 use log::{error, info};
+use rand::{rng, Rng};
+use serde::{de::DeserializeOwned, Serialize};
 
 
-use crate::models::{Redis, CustomRedisError, User};
+use crate::models::{Cache, CacheNamespace, Redis, RedisCache, RedisConfig, CustomRedisError};
 
 impl Redis {
-    pub async fn create_connection() -> Pool {
-        let conn = RedisConfig::from_url("redis://127.0.0.1:6379");
-        let pool = conn.create_pool(Some(deadpool_redis::Runtime::Tokio1))
-            .map_err(|e| error!("Ошибка создания пула redis: {e}")).unwrap();
-        pool
+    /// Пул не подключается сразу, поэтому недоступный Redis здесь не ошибка. Таймауты нужны,
+    /// чтобы запросы не ждали лежащий Redis дольше `timeout_ms`
+    pub async fn create_connection(config: &RedisConfig) -> Result<Pool, CustomRedisError> {
+        let timeout = Some(Duration::from_millis(config.timeout_ms));
+        let mut conn = PoolConfig::from_url(&config.url);
+        conn.pool = Some(deadpool_redis::PoolConfig {
+            timeouts: Timeouts { wait: timeout, create: timeout, recycle: timeout },
+            ..Default::default()
+        });
+        conn.create_pool(Some(deadpool_redis::Runtime::Tokio1)).map_err(|e| {
+            error!("Ошибка создания пула redis: {e}");
+            CustomRedisError::ConnectError
+        })
     }
 
-    pub async fn redis_get(pool: Arc<Pool>, key: &str) -> Result<Vec<User>, CustomRedisError> {
-        let mut conn = match pool.get().await {
+    pub async fn redis_del(redis_pool: Arc<Pool>, key: &str) -> Result<(), CustomRedisError> {
+        let mut conn = match redis_pool.get().await {
             Ok(conn) => conn,
             Err(e) => {
                 error!("Ошибка получения соединения redis: {e}");
@@ -23,31 +34,83 @@ impl Redis {
             }
         };
 
-        let data: Option<String> = match conn.get(key).await {
-            Ok(Some(data)) => data,
-            Ok(None) => {
-                error!("Null данные в Redis");
-                return Err(CustomRedisError::NoneError);
+        let res: Result<(), RedisError> = conn.del(key).await;
+        match res {
+            Ok(()) => {
+                info!("пользователь удален из redis");
+                return Ok(())
             },
+            Err(e) => {
+                error!("Не удалось удалить из Redis: {e}");
+                Err(CustomRedisError::DeleteError)
+            }
+        }
+    }
+
+    pub async fn redis_get_str(pool: Arc<Pool>, key: &str) -> Result<String, CustomRedisError> {
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Ошибка получения соединения redis: {e}");
+                return Err(CustomRedisError::ConnectError)
+            }
+        };
+
+        match conn.get(key).await {
+            Ok(Some(data)) => return Ok(data),
+            Ok(None) => return Err(CustomRedisError::NoneError),
             Err(e) => {
                 error!("Ошибка получения данных из redis: {}", e);
                 return Err(CustomRedisError::SomeError);
             }
+        }
+    }
+
+    pub async fn redis_set_str(pool: Arc<Pool>, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Ошибка получения соединения redis: {e}");
+                return Err(CustomRedisError::ConnectError)
+            }
         };
 
-        let user = match serde_json::from_str(&data.unwrap_or_default()) {
-            Ok(user) => user,
+        let res: Result<(), RedisError> = conn.set_ex(key, value, ttl).await;
+        match res {
+            Ok(_) => return Ok(()),
             Err(e) => {
-                error!("Ошибка десериализации данных из serde_json: {}", e);
+                error!("Ошибка установки данных в redis: {}", e);
                 return Err(CustomRedisError::SomeError);
             }
+        }
+    }
+
+    pub async fn redis_mget_str(pool: Arc<Pool>, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Ошибка получения соединения redis: {e}");
+                return Err(CustomRedisError::ConnectError)
+            }
         };
-        info!("Данные выгружены из Redis!!");
 
-        Ok(user)
+        // MGET с одним ключом redis возвращает не массив, поэтому всегда запрашиваем списком
+        let res: Result<Vec<Option<String>>, RedisError> = deadpool_redis::redis::cmd("MGET").arg(keys).query_async(&mut conn).await;
+        match res {
+            Ok(values) => return Ok(values),
+            Err(e) => {
+                error!("Ошибка получения данных из redis: {}", e);
+                return Err(CustomRedisError::SomeError);
+            }
+        }
     }
 
-    pub async fn redis_set(pool: Arc<Pool>, key: &str, value: Vec<User>) -> Result<(), CustomRedisError> {
+    /// SET EX для каждой пары одним pipeline: MSET не умеет TTL
+    pub async fn redis_mset_str(pool: Arc<Pool>, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        if items.is_empty() {
+            return Ok(())
+        }
+
         let mut conn = match pool.get().await {
             Ok(conn) => conn,
             Err(e) => {
@@ -56,26 +119,41 @@ impl Redis {
             }
         };
 
-        let value = match serde_json::to_string(&value) {
-            Ok(value) => value,
+        let mut pipe = deadpool_redis::redis::pipe();
+        for (key, value) in items {
+            pipe.set_ex(key, value, ttl).ignore();
+        }
+        let res: Result<(), RedisError> = pipe.query_async(&mut conn).await;
+        match res {
+            Ok(()) => return Ok(()),
             Err(e) => {
-                error!("Ошибка serde_json: {e}");
-                return Err(CustomRedisError::SomeError)
+                error!("Ошибка установки данных в redis: {}", e);
+                return Err(CustomRedisError::SomeError);
+            }
+        }
+    }
+
+    pub async fn redis_incr(pool: Arc<Pool>, key: &str) -> Result<i64, CustomRedisError> {
+        let mut conn = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!("Ошибка получения соединения redis: {e}");
+                return Err(CustomRedisError::ConnectError)
             }
         };
 
-        let res: Result<(), RedisError> = conn.set_ex(key, value, 600).await;
+        let res: Result<i64, RedisError> = conn.incr(key, 1).await;
         match res {
-            Ok(_) => return Ok(()),
+            Ok(value) => return Ok(value),
             Err(e) => {
-                error!("Ошибка установки данных в redis: {}", e);
+                error!("Ошибка INCR в redis: {}", e);
                 return Err(CustomRedisError::SomeError);
             }
         }
     }
 
-    pub async fn redis_del(redis_pool: Arc<Pool>, key: &str) -> Result<(), CustomRedisError> {
-        let mut conn = match redis_pool.get().await {
+    pub async fn redis_publish(pool: Arc<Pool>, channel: &str, message: &str) -> Result<(), CustomRedisError> {
+        let mut conn = match pool.get().await {
             Ok(conn) => conn,
             Err(e) => {
                 error!("Ошибка получения соединения redis: {e}");
@@ -83,18 +161,109 @@ impl Redis {
             }
         };
 
-        let res: Result<(), RedisError> = conn.del(key).await;
+        let res: Result<i64, RedisError> = conn.publish(channel, message).await;
         match res {
-            Ok(()) => {
-                info!("пользователь удален из redis");
-                return Ok(())
-            },
+            Ok(_) => return Ok(()),
             Err(e) => {
-                error!("Не удалось удалить из Redis: {e}");
-                Err(CustomRedisError::DeleteError)
+                error!("Ошибка PUBLISH в redis: {}", e);
+                return Err(CustomRedisError::SomeError);
             }
         }
     }
+}
 
+impl RedisCache {
+    pub fn new(pool: Arc<Pool>) -> Self {
+        RedisCache { pool }
+    }
 }
 
+#[async_trait]
+impl Cache for RedisCache {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError> {
+        Redis::redis_get_str(Arc::clone(&self.pool), key).await
+    }
+
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        Redis::redis_set_str(Arc::clone(&self.pool), key, value, ttl).await
+    }
+
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        Redis::redis_mget_str(Arc::clone(&self.pool), keys).await
+    }
+
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        Redis::redis_mset_str(Arc::clone(&self.pool), items, ttl).await
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError> {
+        Redis::redis_del(Arc::clone(&self.pool), key).await
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError> {
+        Redis::redis_incr(Arc::clone(&self.pool), key).await
+    }
+}
+
+impl RedisConfig {
+    /// Добавляет к `ttl` случайные 0..=`ttl_jitter` процентов
+    pub fn jitter(&self, ttl: u64) -> u64 {
+        let max = ttl * self.ttl_jitter / 100;
+        ttl + rng().random_range(0..=max)
+    }
+}
+
+impl CacheNamespace {
+    pub const fn new(name: &'static str, version: u32) -> Self {
+        CacheNamespace { name, version }
+    }
+
+    pub fn key(&self, id: impl Display) -> String {
+        format!("{}:v{}:{id}", self.name, self.version)
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, CustomRedisError> {
+    serde_json::to_string(value).map_err(|e| {
+        error!("Ошибка serde_json: {e}");
+        CustomRedisError::ValueError(e.to_string())
+    })
+}
+
+fn from_json<T: DeserializeOwned>(key: &str, data: &str) -> Result<T, CustomRedisError> {
+    serde_json::from_str(data).map_err(|e| {
+        error!("Ошибка десериализации {key} из кеша: {e}");
+        CustomRedisError::ValueError(e.to_string())
+    })
+}
+
+/// Значения хранятся как JSON. Отсутствующий ключ - `Ok(None)`, ошибки соединения и формата - `Err`
+impl dyn Cache + '_ {
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, CustomRedisError> {
+        match self.get_str(key).await {
+            Ok(data) => from_json(key, &data).map(Some),
+            Err(CustomRedisError::NoneError) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub async fn set<T: Serialize + Sync>(&self, key: &str, value: &T, ttl: u64) -> Result<(), CustomRedisError> {
+        let data = to_json(value)?;
+        self.set_str(key, &data, ttl).await
+    }
+
+    /// Значение с неверным форматом считается промахом, остальные ключи при этом читаются
+    pub async fn mget<T: DeserializeOwned>(&self, keys: &[String]) -> Result<Vec<Option<T>>, CustomRedisError> {
+        let values = self.mget_str(keys).await?;
+        Ok(keys.iter().zip(values)
+            .map(|(key, data)| data.and_then(|data| from_json(key, &data).ok()))
+            .collect())
+    }
+
+    pub async fn mset<T: Serialize + Sync>(&self, items: &[(String, T)], ttl: u64) -> Result<(), CustomRedisError> {
+        let items = items.iter()
+            .map(|(key, value)| Ok((key.clone(), to_json(value)?)))
+            .collect::<Result<Vec<_>, CustomRedisError>>()?;
+        self.mset_str(&items, ttl).await
+    }
+}
@@ -0,0 +1,384 @@
+use std::sync::Arc;
+use async_trait::async_trait;
+use sqlx::{PgPool, query, query_as, query_scalar};
+use log::{error, warn};
+
+use crate::models::{Clock, DataBaseError, HashExtractDb, OwnProfile, PasswordHash, PasswordHashGroup, PgRefreshTokenRepository, PgUserRepository, RefreshTokenRecord, RefreshTokenRepository, RotatedTokenDb, Session, SortOrder, User, UserGrants, UserListQuery, UserPatch, UserRepository, UserSort};
+use crate::services::jwt_service::BASE_ROLE;
+
+impl PgUserRepository {
+    pub fn new(pool: Arc<PgPool>) -> Self {
+        PgUserRepository { pool }
+    }
+
+    async fn get_role_id(&self, role: &str) -> Result<i64, DataBaseError> {
+        let req = r#"SELECT id FROM roles WHERE name = $1"#;
+        match query_scalar(req).bind(role).fetch_optional(&*self.pool).await {
+            Ok(Some(id)) => Ok(id),
+            Ok(None) => Err(DataBaseError::UnknownRole(role.to_owned())),
+            Err(e) => {
+                error!("Ошибка поиска роли: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn create_user(&self, nickname: &str, name: &str, password_hash: &str) -> Result<User, DataBaseError> {
+        let req = r#"INSERT INTO users (nickname, name, password) VALUES ($1, $2, $3) RETURNING id, nickname, name, password"#;
+        match query_as::<_, User>(req).bind(nickname).bind(name).bind(password_hash).fetch_one(&*self.pool).await {
+            Ok(user) => return Ok(user),
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+                return Err(DataBaseError::Conflict("nickname"))
+            },
+            Err(e) => {
+                error!("Ошибка добавления нового пользователя в БД: {e}");
+                return Err(DataBaseError::SaveError);
+            }
+        }
+    }
+
+    async fn delete_user(&self, id: i64) -> Result<(), DataBaseError> {
+        // user_roles и refresh_tokens удаляются по ON DELETE CASCADE
+        let req = r#"DELETE FROM users WHERE id = $1"#;
+        match query(req).bind(id).execute(&*self.pool).await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                error!("Не удалось удалить пользователя {id} из БД: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    async fn get_user_credentials(&self, nickname: &str) -> Result<User, DataBaseError> {
+        let req = r#"SELECT id, nickname, name, password FROM users WHERE nickname = $1"#;
+        match query_as::<_, User>(req).bind(nickname).fetch_optional(&*self.pool).await {
+            Ok(Some(user)) => return Ok(user),
+            Ok(None) => return Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Не удалось найти пользователя в БД: {e}");
+                return Err(DataBaseError::SqlxError);
+            }
+        }
+    }
+
+    async fn get_user(&self, nickname: &str) -> Result<OwnProfile, DataBaseError> {
+        let req = r#"SELECT id, nickname, name FROM users WHERE nickname = $1"#;
+        match query_as::<_, OwnProfile>(req).bind(nickname).fetch_one(&*self.pool).await {
+            Ok(user) => return Ok(user),
+            Err(sqlx::Error::RowNotFound) => return Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Не удалось найти пользователя в БД: {e}");
+                return Err(DataBaseError::SqlxError);
+            }
+        }
+    }
+
+    async fn get_user_by_id(&self, id: &str) -> Result<OwnProfile, DataBaseError> {
+        // id приходит из claims.sub, нечисловой id не может принадлежать пользователю
+        let id = id.parse::<i64>().map_err(|_| DataBaseError::NotFound)?;
+        let req = r#"SELECT id, nickname, name FROM users WHERE id = $1"#;
+        match query_as::<_, OwnProfile>(req).bind(id).fetch_one(&*self.pool).await {
+            Ok(user) => return Ok(user),
+            Err(sqlx::Error::RowNotFound) => return Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Ошибка поиска user по id: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    /// Keyset пагинация по (sort, id): следующая страница начинается после строки с id = `after`
+    async fn find_users(&self, list: &UserListQuery, limit: i64) -> Result<Vec<OwnProfile>, DataBaseError> {
+        let prefix = list.q.as_deref().map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+
+        // в запрос подставляются только имена колонок из enum, значения передаются параметрами
+        let column = match list.sort {
+            UserSort::Id => "id",
+            UserSort::Nickname => "nickname",
+            UserSort::Name => "name",
+        };
+        let (direction, compare) = match list.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        let req = format!(r#"
+            SELECT id, nickname, name FROM users
+            WHERE ($1::BIGINT IS NULL OR ({column}, id) {compare} (SELECT {column}, id FROM users WHERE id = $1))
+                AND ($2::TEXT IS NULL OR lower(nickname) LIKE $2 ESCAPE '\' OR lower(name) LIKE $2 ESCAPE '\')
+            ORDER BY {column} {direction}, id {direction}
+            LIMIT $3"#);
+        let pattern = prefix.map(|q| format!("{}%", q.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")));
+
+        match query_as::<_, OwnProfile>(&req).bind(list.after).bind(pattern).bind(limit).fetch_all(&*self.pool).await {
+            Ok(users) => Ok(users),
+            Err(e) => {
+                error!("Ошибка получения страницы пользователей из ДБ: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    /// Применяет `UserPatch` одним UPDATE
+    async fn update_user(&self, id: &str, patch: &UserPatch, password_hash: Option<&str>) -> Result<User, DataBaseError> {
+        let req = r#"
+            UPDATE users SET
+                nickname = COALESCE($2, nickname),
+                name = COALESCE($3, name),
+                password = COALESCE($4, password)
+            WHERE id = $1
+            RETURNING id, nickname, name, password"#;
+        match query_as::<_, User>(req)
+            .bind(id.parse::<i64>().unwrap_or(-1))
+            .bind(patch.nickname.as_deref())
+            .bind(patch.name.as_deref().map(str::trim))
+            .bind(password_hash)
+            .fetch_optional(&*self.pool).await {
+            Ok(Some(user)) => return Ok(user),
+            Ok(None) => return Err(DataBaseError::NotFound),
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23505") => {
+                return Err(DataBaseError::Conflict("nickname"))
+            },
+            Err(e) => {
+                error!("Ошибка обновления пользователя: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    /// Роли пользователя (включая неявную BASE_ROLE) и права этих ролей
+    async fn get_user_grants(&self, user_id: &str) -> Result<UserGrants, DataBaseError> {
+        let req = r#"SELECT r.name FROM user_roles ur JOIN roles r ON r.id = ur.role_id WHERE ur.user_id = $1 ORDER BY r.name"#;
+        let mut roles: Vec<String> = match query_scalar(req).bind(user_id.parse::<i64>().unwrap_or(-1)).fetch_all(&*self.pool).await {
+            Ok(roles) => roles,
+            Err(e) => {
+                error!("Ошибка получения ролей пользователя: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        };
+        if !roles.iter().any(|role| role == BASE_ROLE) {
+            roles.insert(0, BASE_ROLE.to_owned());
+        }
+
+        let req = r#"
+            SELECT DISTINCT p.name FROM roles r
+            JOIN role_permissions rp ON rp.role_id = r.id
+            JOIN permissions p ON p.id = rp.permission_id
+            WHERE r.name = ANY($1)
+            ORDER BY p.name"#;
+        match query_scalar(req).bind(&roles).fetch_all(&*self.pool).await {
+            Ok(scopes) => Ok(UserGrants { roles, scopes }),
+            Err(e) => {
+                error!("Ошибка получения прав пользователя: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    /// Выдаёт роль, повторная выдача ничего не меняет
+    async fn grant_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError> {
+        let role_id = self.get_role_id(role).await?;
+        let req = r#"INSERT INTO user_roles (user_id, role_id) VALUES ($1, $2) ON CONFLICT DO NOTHING"#;
+        match query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(role_id).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            // нет такого пользователя
+            Err(sqlx::Error::Database(e)) if e.code().as_deref() == Some("23503") => Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Не удалось выдать роль: {e}");
+                return Err(DataBaseError::SaveError);
+            },
+        }
+    }
+
+    async fn revoke_role(&self, user_id: &str, role: &str) -> Result<(), DataBaseError> {
+        let role_id = self.get_role_id(role).await?;
+        let req = r#"DELETE FROM user_roles WHERE user_id = $1 and role_id = $2"#;
+        match query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(role_id).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Не удалось отозвать роль: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    async fn save_security_event(&self, user_id: &str, kind: &str, details: &str) -> Result<(), DataBaseError> {
+        let req = r#"INSERT INTO security_events (user_id, kind, details) VALUES ($1, $2, $3)"#;
+        match query(req).bind(user_id.parse::<i64>().ok()).bind(kind).bind(details).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Не удалось сохранить security event: {e}");
+                return Err(DataBaseError::SaveError);
+            },
+        }
+    }
+
+    async fn replace_password_hash(&self, user_id: i64, old_hash: &PasswordHash, new_hash: &str) -> Result<bool, DataBaseError> {
+        let req = r#"UPDATE users SET password = $3 WHERE id = $1 AND password = $2"#;
+        match query(req).bind(user_id).bind(old_hash).bind(new_hash).execute(&*self.pool).await {
+            Ok(res) => return Ok(res.rows_affected() > 0),
+            Err(e) => {
+                error!("Ошибка обновления хеша пароля: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    /// Части строки хеша: 2 - алгоритм, 3 - версия, 4 - параметры, 6 - сам хеш
+    async fn password_hash_groups(&self) -> Result<Vec<PasswordHashGroup>, DataBaseError> {
+        let req = r#"
+            SELECT min(password) AS sample, count(*) AS users FROM users
+            GROUP BY split_part(password, '$', 2), split_part(password, '$', 3), split_part(password, '$', 4), length(split_part(password, '$', 6))"#;
+        match query_as::<_, PasswordHashGroup>(req).fetch_all(&*self.pool).await {
+            Ok(groups) => return Ok(groups),
+            Err(e) => {
+                error!("Ошибка подсчёта параметров хешей паролей: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    async fn ping(&self) -> Result<(), DataBaseError> {
+        match query("SELECT 1").execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("БД не отвечает: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+}
+
+impl PgRefreshTokenRepository {
+    pub fn new(pool: Arc<PgPool>, clock: Arc<dyn Clock>) -> Self {
+        PgRefreshTokenRepository { pool, clock }
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepository for PgRefreshTokenRepository {
+    async fn save_ref_token(&self, token: &RefreshTokenRecord) -> Result<(), DataBaseError> {
+        let req = r#"INSERT INTO refresh_tokens (jti, user_id, token_hash, expires_at, created_at, family_id, user_agent, ip, last_used_at) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)"#;
+        match query(req).bind(&token.jti).bind(token.user_id).bind(&token.token_hash).bind(token.expires_at).bind(token.created_at).bind(&token.family_id)
+            .bind(&token.device.user_agent).bind(&token.device.ip).bind(token.last_used_at).execute(&*self.pool).await {
+            Ok(_) => return Ok(()),
+            Err(e) => {
+                error!("Не удалось сохранить refresh токен в БД: {e}");
+                return Err(DataBaseError::SaveError)
+            }
+        }
+    }
+
+    async fn get_token_hash(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        let req = r#"SELECT token_hash FROM refresh_tokens WHERE user_id = $1 and jti = $2"#;
+        match query_as::<_, HashExtractDb>(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(jti).fetch_one(&*self.pool).await {
+            Ok(res) => return Ok(res.token_hash),
+            Err(e) => {
+                error!("Ошибка поиска токена в базе данных: {e}");
+                return Err(DataBaseError::NotFound)
+            }
+        }
+    }
+
+    async fn find_token_by_digest(&self, digest: &str) -> Result<(i64, String), DataBaseError> {
+        let req = r#"SELECT user_id, jti FROM refresh_tokens WHERE token_hash = $1"#;
+        match query_as::<_, (i64, String)>(req).bind(digest).fetch_optional(&*self.pool).await {
+            Ok(Some(row)) => return Ok(row),
+            Ok(None) => return Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Ошибка поиска токена по digest: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    async fn rotate_ref_token(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        let user_id = user_id.parse::<i64>().unwrap_or(-1);
+        let req = r#"UPDATE refresh_tokens SET rotated_at = $3 WHERE user_id = $1 and jti = $2 and rotated_at IS NULL RETURNING family_id, rotated_at"#;
+        match query_as::<_, RotatedTokenDb>(req).bind(user_id).bind(jti).bind(self.clock.now()).fetch_optional(&*self.pool).await {
+            Ok(Some(row)) => return Ok(row.family_id),
+            Ok(None) => (),
+            Err(e) => {
+                error!("Не удалось ротировать refresh токен: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+
+        let req = r#"SELECT family_id, rotated_at FROM refresh_tokens WHERE user_id = $1 and jti = $2"#;
+        match query_as::<_, RotatedTokenDb>(req).bind(user_id).bind(jti).fetch_optional(&*self.pool).await {
+            Ok(Some(row)) => {
+                warn!("Повторное использование refresh токена {jti}, семья {}", row.family_id);
+                return Err(DataBaseError::TokenReuse { family_id: row.family_id, rotated_at: row.rotated_at })
+            },
+            Ok(None) => return Err(DataBaseError::NotFound),
+            Err(e) => {
+                error!("Ошибка поиска refresh токена: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    async fn revoke_ref_family(&self, user_id: &str, family_id: &str) -> Result<(), DataBaseError> {
+        let req = r#"DELETE FROM refresh_tokens WHERE user_id = $1 and family_id = $2"#;
+        match query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(family_id).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Не удалось отозвать семью refresh токенов: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    async fn del_ref_token(&self, user_id: &str, jti: &str) -> Result<(), DataBaseError> {
+        let req = r#"DELETE FROM refresh_tokens WHERE user_id = $1 and family_id = (SELECT family_id FROM refresh_tokens WHERE user_id = $1 and jti = $2)"#;
+        match query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(jti).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Не удалось удалить использованный refresh токен из БД: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    /// Выход на всех устройствах: удаляет все refresh токены пользователя
+    async fn del_all_ref_tokens(&self, user_id: &str) -> Result<(), DataBaseError> {
+        let req = r#"DELETE FROM refresh_tokens WHERE user_id = $1"#;
+        match query(req).bind(user_id.parse::<i64>().unwrap_or(-1)).execute(&*self.pool).await {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Не удалось удалить refresh токены пользователя: {e}");
+                return Err(DataBaseError::SqlxError);
+            },
+        }
+    }
+
+    /// `last_used_at` обновляется при каждой ротации refresh токена
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<Session>, DataBaseError> {
+        let req = r#"
+            SELECT r.jti, r.last_used_at, r.expires_at, r.user_agent, r.ip,
+                (SELECT min(f.created_at) FROM refresh_tokens f WHERE f.family_id = r.family_id) AS started_at
+            FROM refresh_tokens r
+            WHERE r.user_id = $1 and r.rotated_at IS NULL and r.expires_at > $2
+            ORDER BY r.last_used_at DESC"#;
+        match query_as::<_, Session>(req).bind(user_id.parse::<i64>().unwrap_or(-1)).bind(self.clock.now()).fetch_all(&*self.pool).await {
+            Ok(sessions) => Ok(sessions),
+            Err(e) => {
+                error!("Ошибка получения сессий пользователя: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+
+    async fn purge_expired_tokens(&self) -> Result<u64, DataBaseError> {
+        let req = r#"DELETE FROM refresh_tokens WHERE expires_at <= $1"#;
+        match query(req).bind(self.clock.now()).execute(&*self.pool).await {
+            Ok(res) => return Ok(res.rows_affected()),
+            Err(e) => {
+                error!("Не удалось удалить истёкшие refresh токены: {e}");
+                return Err(DataBaseError::SqlxError)
+            }
+        }
+    }
+}
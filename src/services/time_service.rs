@@ -1,5 +1,5 @@
 use chrono::{DateTime, TimeZone, Utc};
-use crate::models::{TimeCustom, TimeCustomError};
+use crate::models::{Clock, SystemClock, TimeCustom, TimeCustomError};
 use log::error;
 
 impl TimeCustom {
@@ -22,4 +22,10 @@ impl TimeCustom {
 
         Ok(time3)
     }
-}
\ No newline at end of file
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
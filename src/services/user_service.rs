@@ -0,0 +1,27 @@
+use std::fmt;
+
+use crate::models::{OwnProfile, PasswordHash, PublicProfile, User};
+
+impl PasswordHash {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PasswordHash(***)")
+    }
+}
+
+impl From<&User> for OwnProfile {
+    fn from(user: &User) -> Self {
+        OwnProfile { id: user.id, nickname: user.nickname.clone(), name: user.name.clone() }
+    }
+}
+
+impl From<OwnProfile> for PublicProfile {
+    fn from(profile: OwnProfile) -> Self {
+        PublicProfile { nickname: profile.nickname, name: profile.name }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::models::{FieldError, LoginForm, RegisterForm, UserListQuery, UserPatch};
+
+const NICKNAME_LEN: (usize, usize) = (3, 32);
+const NAME_LEN: (usize, usize) = (1, 64);
+const PASSWORD_LEN: (usize, usize) = (8, 128);
+const SEARCH_LEN: (usize, usize) = (1, 64);
+/// Размер страницы `/all`: по умолчанию и максимальный
+const PAGE_LIMIT: (i64, i64) = (20, 100);
+
+fn check_len(field: &'static str, value: &str, (min, max): (usize, usize), errors: &mut Vec<FieldError>) {
+    let len = value.chars().count();
+    if len < min || len > max {
+        errors.push(FieldError {
+            field,
+            message: format!("must be between {min} and {max} characters"),
+        });
+    }
+}
+
+fn check_nickname(nickname: &str, errors: &mut Vec<FieldError>) {
+    check_len("nickname", nickname, NICKNAME_LEN, errors);
+    // nickname используется в пути /profile/{nickname}
+    if !nickname.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        errors.push(FieldError {
+            field: "nickname",
+            message: "may contain only latin letters, digits, '_', '-' and '.'".to_owned(),
+        });
+    }
+}
+
+impl RegisterForm {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        check_nickname(&self.nickname, &mut errors);
+        check_len("name", self.name.trim(), NAME_LEN, &mut errors);
+        check_len("password", &self.password, PASSWORD_LEN, &mut errors);
+
+        if errors.is_empty() {
+            return Ok(())
+        }
+        Err(errors)
+    }
+}
+
+impl LoginForm {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if self.nickname.is_empty() {
+            errors.push(FieldError { field: "nickname", message: "must not be empty".to_owned() });
+        }
+        if self.password.is_empty() {
+            errors.push(FieldError { field: "password", message: "must not be empty".to_owned() });
+        }
+
+        if errors.is_empty() {
+            return Ok(())
+        }
+        Err(errors)
+    }
+}
+
+impl UserPatch {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if self.is_empty() {
+            errors.push(FieldError { field: "body", message: "at least one field must be set".to_owned() });
+        }
+        if let Some(nickname) = &self.nickname {
+            check_nickname(nickname, &mut errors);
+        }
+        if let Some(name) = &self.name {
+            check_len("name", name.trim(), NAME_LEN, &mut errors);
+        }
+        if let Some(password) = &self.password {
+            check_len("password", password, PASSWORD_LEN, &mut errors);
+        }
+
+        if errors.is_empty() {
+            return Ok(())
+        }
+        Err(errors)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nickname.is_none() && self.name.is_none() && self.password.is_none()
+    }
+}
+
+impl UserListQuery {
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut errors = Vec::new();
+
+        if let Some(limit) = self.limit {
+            if !(1..=PAGE_LIMIT.1).contains(&limit) {
+                errors.push(FieldError { field: "limit", message: format!("must be between 1 and {}", PAGE_LIMIT.1) });
+            }
+        }
+        if let Some(q) = &self.q {
+            check_len("q", q, SEARCH_LEN, &mut errors);
+        }
+
+        if errors.is_empty() {
+            return Ok(())
+        }
+        Err(errors)
+    }
+
+    pub fn page_limit(&self) -> i64 {
+        self.limit.unwrap_or(PAGE_LIMIT.0)
+    }
+}
@@ -0,0 +1,60 @@
+use rp::models::*;
+use axum::{body::Body, http::{Request, StatusCode}, routing::get, Router};
+use tower::ServiceExt;
+
+fn claims(role: &str, scopes: &[&str]) -> Claims {
+    Claims {
+        sub: "1".to_owned(),
+        iss: "server".to_owned(),
+        aud: "all".to_owned(),
+        exp: 0,
+        iat: 0,
+        iat_ms: 0,
+        jti: "jti".to_owned(),
+        role: role.to_owned(),
+        roles: vec![role.to_owned()],
+        scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+    }
+}
+
+fn app() -> Router {
+    let admin = Router::new()
+        .route("/admin", get(|| async { "admin" }))
+        .route_layer(RequireRole("Admin"));
+    let roles = Router::new()
+        .route("/roles", get(|| async { "roles" }))
+        .route_layer(RequirePermission("roles:write"));
+
+    Router::new()
+        .route("/me", get(|AuthUser(claims): AuthUser| async move { claims.sub }))
+        .route("/maybe", get(|OptionalAuthUser(claims): OptionalAuthUser| async move {
+            claims.map(|claims| claims.sub).unwrap_or("anonymous".to_owned())
+        }))
+        .merge(admin)
+        .merge(roles)
+}
+
+async fn status(uri: &str, claims: Option<Claims>) -> StatusCode {
+    let mut req = Request::builder().uri(uri).body(Body::empty()).unwrap();
+    if let Some(claims) = claims {
+        req.extensions_mut().insert(claims);
+    }
+    app().oneshot(req).await.unwrap().status()
+}
+
+#[tokio::test]
+async fn auth_user_extractor_test() {
+    assert_eq!(status("/me", None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(status("/me", Some(claims("User", &[]))).await, StatusCode::OK);
+    assert_eq!(status("/maybe", None).await, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn access_layer_test() {
+    assert_eq!(status("/admin", None).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(status("/admin", Some(claims("User", &[]))).await, StatusCode::FORBIDDEN);
+    assert_eq!(status("/admin", Some(claims("Admin", &["roles:write"]))).await, StatusCode::OK);
+
+    assert_eq!(status("/roles", Some(claims("User", &[]))).await, StatusCode::FORBIDDEN);
+    assert_eq!(status("/roles", Some(claims("Admin", &["roles:write"]))).await, StatusCode::OK);
+}
@@ -1,6 +1,5 @@
 use rp::models::*;
 use std::{path::Path, time::Instant};
-use image;
 
 #[tokio::test]
 async fn create_key_test() {
@@ -20,31 +19,43 @@ async fn create_hex_key_test() {
 
 #[tokio::test]
 async fn encrypt_test() {
-    let res = Aes::encrypt_data("Привет мир").await;
+    let config = Config::load().unwrap();
+    let res = Aes::encrypt_data("Привет мир", &config.aes).await;
     assert!(res.is_ok())
 }
 
 #[tokio::test]
 async fn decrypt_test() {
-    let res = Aes::encrypt_data("Привет мир").await.unwrap();
-    let res2 = Aes::decrypt_data(&res).await;
+    let config = Config::load().unwrap();
+    let res = Aes::encrypt_data("Привет мир", &config.aes).await.unwrap();
+    let res2 = Aes::decrypt_data(&res, &config.aes).await;
     assert!(res2.is_ok())
 }
 
 #[tokio::test]
 async fn encrypt_file_test() {
+    let config = Config::load().unwrap();
     let path = Path::new("src/static/Texts.docx");
-    let res = Aes::encrypt_file(path).await;
+    let res = Aes::encrypt_file(path, &config.aes).await;
     //println!("{:?}", res.clone().unwrap());
     assert!(res.is_ok())
 }
 
 #[tokio::test]
 async fn decrypt_file_test() {
+    let config = Config::load().unwrap();
     let path = Path::new("src/static/qrcode.png");
-    let encrypted_file = Aes::encrypt_file(path).await.unwrap();
-    let decrypted_file = Aes::decrypt_file(&encrypted_file).await.unwrap();
+    let encrypted_file = Aes::encrypt_file(path, &config.aes).await.unwrap();
+    let decrypted_file = Aes::decrypt_file(&encrypted_file, &config.aes).await.unwrap();
     let check = image::load_from_memory(&decrypted_file);
     assert!(check.is_ok());
 }
 
+
+#[tokio::test]
+async fn decrypt_short_data_test() {
+    let config = Config::load().unwrap();
+    assert!(matches!(Aes::decrypt_data(&[1, 2, 3], &config.aes).await, Err(AesError::InvalidData)));
+    assert!(matches!(Aes::decrypt_file(&[], &config.aes).await, Err(AesError::InvalidData)));
+    assert!(matches!(Aes::encrypt_file(Path::new("src/static/missing.txt"), &config.aes).await, Err(AesError::ReadFileError)));
+}
@@ -0,0 +1,60 @@
+use rp::{app::build_router, models::*};
+use axum::{body::{to_bytes, Body}, http::{header, Method, Request, StatusCode}, Router};
+use rand::random;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tower::ServiceExt;
+
+async fn app() -> (Router, AppState) {
+    let config = Config::load().unwrap();
+    let state = AppState::connect(config, Arc::new(SystemClock)).await.unwrap();
+    (build_router(state.clone()), state)
+}
+
+async fn send(app: &Router, method: Method, uri: &str, token: Option<&str>, body: Option<Value>) -> (StatusCode, Value) {
+    let mut req = Request::builder().method(method).uri(uri);
+    if let Some(token) = token {
+        req = req.header(header::AUTHORIZATION, format!("Bearer {token}"));
+    }
+    let req = match body {
+        Some(body) => req.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+        None => req.body(Body::empty()),
+    };
+
+    let res = app.clone().oneshot(req.unwrap()).await.unwrap();
+    let status = res.status();
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+#[tokio::test]
+async fn auth_flow_test() {
+    let (app, state) = app().await;
+    let nickname = format!("app-user{}", random::<u32>());
+
+    let (status, tokens) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", None,
+        Some(json!({"nickname": nickname, "name": "App", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(tokens["expires_in"], state.config.jwt.access_ttl);
+    let access_token = tokens["access_token"].as_str().unwrap();
+
+    let (status, user) = send(&app, Method::PATCH, "/api/v1/users/me", Some(access_token), Some(json!({"name": "Renamed"}))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(user["name"], "Renamed");
+
+    let (status, sessions) = send(&app, Method::GET, "/api/v1/sessions", Some(access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sessions.as_array().map(Vec::len), Some(1));
+
+    let (status, error) = send(&app, Method::GET, "/api/v1/sessions", Some("junk"), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "unauthorized");
+    assert!(error["request_id"].is_string());
+
+    let (status, error) = send(&app, Method::GET, "/api/v1/admin/users/1/roles", Some(access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(error["error"], "forbidden");
+
+    let pool = DataBase::create_connection(&state.config.database).await.unwrap();
+    sqlx::query("DELETE FROM users WHERE id = $1").bind(user["id"].as_i64()).execute(&pool).await.unwrap();
+}
@@ -1,38 +1,56 @@
 use rp::models::*;
+use rp::services::database_service::{USERS_BY_ID, USERS_BY_NICKNAME};
+use chrono::{DateTime, DurationRound, Utc};
 use rand::random;
 use std::sync::Arc;
 use std::time::Instant;
 
+/// Часы приложения, отстающие от часов БД
+struct FixedClock(DateTime<Utc>);
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
 #[tokio::test]
 async fn test_get_pool() {
-    let pool = DataBase::create_connection().await;
+    let config = Config::load().unwrap();
+    let pool = DataBase::create_connection(&config.database).await.unwrap();
     let pool_str = format!("{:?}", pool);
     assert!(!pool_str.is_empty())
 }
 
 #[tokio::test]
 async fn save_user_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
     let random_id: u32 = random();
-    let pool = Arc::new(DataBase::create_connection().await);
-    let redis_pool = Arc::new(Redis::create_connection().await);
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
     let time = Instant::now();
 
-    let mut transaction = pool.begin().await.expect("Не удалось превратить pool в транзакцию");
-    let res = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "123", &mut transaction, Arc::clone(&redis_pool)).await;
+    let res = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "123", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await;
     
     let stop = time.elapsed().as_millis();
     println!("{:?}, время: {}", res.clone().unwrap(), stop); // User { id: 17, nickname: "test-user688311194", name: "test-user", password: ... }, u128
     assert!(res.is_ok());
-    transaction.rollback().await.expect("Не удалось rollback транзакцию");
-    println!("Rollback произошел");
+
+    let res = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "123", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await;
+    assert!(matches!(res, Err(DataBaseError::Conflict("nickname"))));
+    sqlx::query("DELETE FROM users WHERE nickname = $1").bind(format!("test-user{}", random_id)).execute(&*pool).await.unwrap();
 }
 
 #[tokio::test]
 async fn get_user_test() {
+    let config = Config::load().unwrap();
     let time = Instant::now();
-    let pool = Arc::new(DataBase::create_connection().await);
-    let redis_pool = Arc::new(Redis::create_connection().await);
-    let res = DataBase::get_user("test-user688311194", Arc::clone(&pool), Arc::clone(&redis_pool)).await;
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+    let res = DataBase::get_user("test-user688311194", users, cache, Arc::new(SingleFlight::new()), &config).await;
     assert!(res.is_ok());
     let stop = time.elapsed().as_millis();
     println!("{:?}, время: {}", res.clone().unwrap(), stop);
@@ -40,9 +58,242 @@ async fn get_user_test() {
 
 #[tokio::test]
 async fn save_ref_token_test() {
-    let pool = Arc::new(DataBase::create_connection().await);
-    let res = DataBase::save_ref_token("non-valid-token", pool).await;
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let keys = Arc::new(KeyStore::load(&config.jwt, Arc::new(SystemClock)).await.unwrap());
+    let res = DataBase::save_ref_token("non-valid-token", "family", &DeviceInfo::default(), keys, Arc::new(PgRefreshTokenRepository::new(pool, Arc::new(SystemClock))), Arc::clone(&hasher)).await;
     assert!(res.is_err())
     
 }
 
+
+#[tokio::test]
+async fn refresh_token_reuse_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let app_now = (Utc::now() - chrono::Duration::hours(1)).duration_trunc(chrono::Duration::seconds(1)).unwrap();
+    let tokens: Arc<dyn RefreshTokenRepository> = Arc::new(PgRefreshTokenRepository::new(Arc::clone(&pool), Arc::new(FixedClock(app_now))));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+    let keys = Arc::new(KeyStore::load(&config.jwt, Arc::new(SystemClock)).await.unwrap());
+
+    let user = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+    let id = user.id.to_string();
+
+    let first = Jwt::create_ref_token(&id, "User", Arc::clone(&keys)).await.unwrap();
+    DataBase::save_ref_token(&first, "test-family", &DeviceInfo::default(), Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher)).await.unwrap();
+    let first_claims = Jwt::verify_ref_token(&first, Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher), true).await.unwrap();
+
+    let family = tokens.rotate_ref_token(&id, &first_claims.jti).await.unwrap();
+    assert_eq!(family, "test-family");
+    let second = Jwt::create_ref_token(&id, "User", Arc::clone(&keys)).await.unwrap();
+    DataBase::save_ref_token(&second, &family, &DeviceInfo::default(), Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher)).await.unwrap();
+
+    let reuse = tokens.rotate_ref_token(&id, &first_claims.jti).await;
+    // время ротации - по часам приложения, а не now() в Postgres
+    assert!(matches!(reuse, Err(DataBaseError::TokenReuse { family_id, rotated_at: Some(rotated_at) }) if family_id == "test-family" && rotated_at == app_now));
+
+    tokens.revoke_ref_family(&id, "test-family").await.unwrap();
+    assert!(Jwt::verify_ref_token(&second, Arc::clone(&keys), Arc::clone(&tokens), Arc::clone(&hasher), true).await.is_err());
+
+    // истёкшая строка удаляется фоновой очисткой
+    let expired = chrono::Utc::now() - chrono::Duration::days(1);
+    tokens.save_ref_token(&RefreshTokenRecord {
+        jti: format!("expired-{random_id}"),
+        user_id: user.id,
+        token_hash: format!("expired-{random_id}"),
+        family_id: "expired-family".to_owned(),
+        created_at: expired,
+        expires_at: expired,
+        last_used_at: expired,
+        rotated_at: None,
+        device: DeviceInfo::default(),
+    }).await.unwrap();
+    assert!(tokens.purge_expired_tokens().await.unwrap() >= 1);
+    assert!(tokens.get_token_hash(&id, &format!("expired-{random_id}")).await.is_err());
+
+    // токены удаляются вместе с пользователем
+    users.delete_user(user.id).await.unwrap();
+    assert!(matches!(users.get_user_credentials(&user.nickname).await, Err(DataBaseError::NotFound)));
+    assert!(tokens.get_token_hash(&id, &first_claims.jti).await.is_err());
+}
+
+#[tokio::test]
+async fn user_roles_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+
+    let user = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+    let id = user.id.to_string();
+
+    let grants = users.get_user_grants(&id).await.unwrap();
+    assert_eq!(grants.roles, vec!["User"]);
+    assert_eq!(grants.primary_role(), "User");
+
+    users.grant_role(&id, "Admin").await.unwrap();
+    users.grant_role(&id, "Admin").await.unwrap();
+    let grants = users.get_user_grants(&id).await.unwrap();
+    assert_eq!(grants.roles, vec!["User", "Admin"]);
+    assert_eq!(grants.primary_role(), "Admin");
+    assert!(grants.scopes.contains(&"roles:write".to_owned()));
+
+    assert!(matches!(users.grant_role(&id, "missing-role").await, Err(DataBaseError::UnknownRole(_))));
+    assert!(matches!(users.grant_role("-1", "Admin").await, Err(DataBaseError::NotFound)));
+
+    users.revoke_role(&id, "Admin").await.unwrap();
+    let grants = users.get_user_grants(&id).await.unwrap();
+    assert_eq!(grants.roles, vec!["User"]);
+    assert!(grants.scopes.is_empty());
+
+    sqlx::query("DELETE FROM users WHERE id = $1").bind(user.id).execute(&*pool).await.unwrap();
+}
+
+#[tokio::test]
+async fn update_user_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+
+    let first = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+    let second = DataBase::save_user(&format!("test-user{}-2", random_id), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+
+    let patch = UserPatch { name: Some("O'Brien'; DROP TABLE users; --".to_owned()), ..Default::default() };
+    let user = DataBase::update_user(&first.id.to_string(), &patch, Arc::clone(&users), Arc::clone(&hasher), &config).await.unwrap();
+    assert_eq!(user.name, "O'Brien'; DROP TABLE users; --");
+    assert_eq!(user.nickname, first.nickname);
+    assert_eq!(user.password, first.password);
+
+    let patch = UserPatch { nickname: Some(second.nickname.clone()), ..Default::default() };
+    let res = DataBase::update_user(&first.id.to_string(), &patch, Arc::clone(&users), Arc::clone(&hasher), &config).await;
+    assert!(matches!(res, Err(DataBaseError::Conflict("nickname"))));
+
+    sqlx::query("DELETE FROM users WHERE id = ANY($1)").bind(vec![first.id, second.id]).execute(&*pool).await.unwrap();
+}
+
+#[tokio::test]
+async fn user_cache_has_no_hash_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let redis_pool = Arc::new(Redis::create_connection(&config.redis).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+
+    let user = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "12345678", users, Arc::new(RedisCache::new(Arc::clone(&redis_pool))), Arc::clone(&hasher), &config).await.unwrap();
+    assert!(user.password.as_str().starts_with("$argon2"));
+    assert!(!format!("{:?}", user).contains("$argon2"));
+
+    let cached = Redis::redis_get_str(Arc::clone(&redis_pool), &USERS_BY_ID.key(user.id)).await.unwrap();
+    assert!(!cached.contains("$argon2"));
+    assert!(!serde_json::to_string(&OwnProfile::from(&user)).unwrap().contains("password"));
+
+    sqlx::query("DELETE FROM users WHERE id = $1").bind(user.id).execute(&*pool).await.unwrap();
+    Redis::redis_del(Arc::clone(&redis_pool), &USERS_BY_ID.key(user.id)).await.unwrap();
+    Redis::redis_del(Arc::clone(&redis_pool), &USERS_BY_NICKNAME.key(&user.nickname)).await.unwrap();
+}
+
+#[tokio::test]
+async fn users_page_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+    let flights = Arc::new(SingleFlight::new());
+    let prefix = format!("Page_{random_id}-");
+
+    let mut ids = Vec::new();
+    for n in 0..3 {
+        let user = DataBase::save_user(&format!("{prefix}{n}"), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+        ids.push(user.id);
+    }
+    DataBase::invalidate_users_pages(Arc::clone(&cache)).await;
+
+    // `_` в префиксе не должен работать как шаблон LIKE
+    let list = UserListQuery { q: Some(prefix.to_uppercase()), limit: Some(2), ..Default::default() };
+    let first = DataBase::get_users_page(&list, Arc::clone(&users), Arc::clone(&cache), Arc::clone(&flights), &config).await.unwrap();
+    let nicknames: Vec<String> = first.items.iter().map(|user| user.nickname.clone()).collect();
+    assert_eq!(nicknames, vec![format!("{prefix}0"), format!("{prefix}1")]);
+    assert_eq!(first.next_after, Some(ids[1]));
+
+    let list = UserListQuery { after: first.next_after, ..list };
+    let second = DataBase::get_users_page(&list, Arc::clone(&users), Arc::clone(&cache), Arc::clone(&flights), &config).await.unwrap();
+    assert_eq!(second.items.len(), 1);
+    assert_eq!(second.next_after, None);
+
+    let list = UserListQuery { q: Some(prefix.clone()), sort: UserSort::Nickname, order: SortOrder::Desc, ..Default::default() };
+    let desc = DataBase::get_users_page(&list, Arc::clone(&users), Arc::clone(&cache), Arc::clone(&flights), &config).await.unwrap();
+    assert_eq!(desc.items.first().map(|user| user.nickname.clone()), Some(format!("{prefix}2")));
+
+    assert!(DataBase::get_users_page(&UserListQuery { q: Some(format!("Page%{random_id}")), ..Default::default() },
+        Arc::clone(&users), Arc::clone(&cache), Arc::clone(&flights), &config).await.unwrap().items.is_empty());
+
+    sqlx::query("DELETE FROM users WHERE id = ANY($1)").bind(&ids).execute(&*pool).await.unwrap();
+    DataBase::invalidate_users_pages(Arc::clone(&cache)).await;
+}
+
+#[tokio::test]
+async fn redis_event_bus_test() {
+    let config = Config::load().unwrap();
+    let pool = Arc::new(Redis::create_connection(&config.redis).await.unwrap());
+    let bus: Arc<dyn EventBus> = Arc::new(RedisEventBus::new(pool, Arc::new(CircuitBreaker::new(config.redis.failure_threshold)), &config.redis));
+    let mut events = bus.subscribe();
+
+    // подписка открывается в фоне, поэтому публикуем, пока событие не придёт
+    let event = CacheEvent::TokensRevoked { user_id: format!("{}", random::<u32>()) };
+    let received = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            bus.publish(&event).await.unwrap();
+            if let Ok(Ok(received)) = tokio::time::timeout(std::time::Duration::from_millis(200), events.recv()).await {
+                return received
+            }
+        }
+    }).await.unwrap();
+    assert_eq!(received, event);
+}
+
+#[tokio::test]
+async fn password_rehash_test() {
+    let config = Config::load().unwrap();
+    let hasher = Arc::new(HashPool::new(&config.argon));
+    let random_id: u32 = random();
+    let pool = Arc::new(DataBase::create_connection(&config.database).await.unwrap());
+    let users: Arc<dyn UserRepository> = Arc::new(PgUserRepository::new(Arc::clone(&pool)));
+    let cache: Arc<dyn Cache> = Arc::new(RedisCache::new(Arc::new(Redis::create_connection(&config.redis).await.unwrap())));
+
+    let user = DataBase::save_user(&format!("test-user{}", random_id), "test-user", "12345678", Arc::clone(&users), Arc::clone(&cache), Arc::clone(&hasher), &config).await.unwrap();
+    let before = DataBase::password_hash_report(Arc::clone(&users), &config).await.unwrap();
+    assert!(before.total >= 1);
+    assert!(before.groups.iter().any(|group| !group.legacy && group.params.as_ref().is_some_and(|params| params.iterations == config.argon.iterations)));
+
+    // политика стала строже: пересчёт после входа записывает хеш с новыми параметрами
+    let mut stricter = config.clone();
+    stricter.argon.iterations += 1;
+    assert!(Argon::needs_rehash(user.password.as_str(), &stricter.argon));
+    DataBase::rehash_password(&user, "12345678", Arc::clone(&users), Arc::clone(&hasher), &stricter).await;
+
+    let updated = users.get_user_credentials(&user.nickname).await.unwrap();
+    assert!(!Argon::needs_rehash(updated.password.as_str(), &stricter.argon));
+    Argon::verify_hash(updated.password.as_str(), "12345678", &hasher).await.unwrap();
+    // старый хеш уже не совпадает, поэтому повторная замена ничего не делает
+    assert!(!users.replace_password_hash(user.id, &user.password, "other").await.unwrap());
+
+    sqlx::query("DELETE FROM users WHERE id = $1").bind(user.id).execute(&*pool).await.unwrap();
+}
+
+#[tokio::test]
+async fn database_connect_error_test() {
+    let config = DatabaseConfig { url: "postgres://postgres@localhost/rpdb?sslmode=bogus".to_owned() };
+    assert!(matches!(DataBase::create_connection(&config).await, Err(StartupError::Database(_))));
+}
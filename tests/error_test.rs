@@ -0,0 +1,74 @@
+use rp::models::*;
+use axum::{body::{to_bytes, Body}, http::{header, Request, StatusCode}, response::Response, routing::get, Router};
+use tower::ServiceExt;
+use tower_http::catch_panic::CatchPanicLayer;
+
+fn app() -> Router {
+    Router::new()
+        .route("/conflict", get(|| async { Err::<(), _>(AppError::DataBase(DataBaseError::Conflict("nickname"))) }))
+        .route("/unauthorized", get(|| async { Err::<(), _>(AppError::Unauthorized) }))
+        .route("/internal", get(|| async { Err::<(), _>(AppError::DataBase(DataBaseError::SaveError)) }))
+        .route("/ok", get(|| async { "ok" }))
+        .route("/panic", get(|| async { if true { panic!("handler bug") } }))
+        .layer(CatchPanicLayer::custom(AppError::from_panic))
+        .layer(ErrorLayer)
+}
+
+async fn call(uri: &str, accept: &str, request_id: Option<&str>) -> (Response, String) {
+    let mut req = Request::builder().uri(uri).header(header::ACCEPT, accept);
+    if let Some(request_id) = request_id {
+        req = req.header("x-request-id", request_id);
+    }
+    let res = app().oneshot(req.body(Body::empty()).unwrap()).await.unwrap();
+    let (parts, body) = res.into_parts();
+    let body = to_bytes(body, usize::MAX).await.unwrap();
+    (Response::from_parts(parts, Body::empty()), String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[tokio::test]
+async fn error_json_test() {
+    let (res, body) = call("/conflict", "application/json", Some("req-1")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "nickname_taken");
+    assert_eq!(body["fields"][0]["field"], "nickname");
+    assert_eq!(body["request_id"], "req-1");
+
+    let (res, body) = call("/unauthorized", "*/*", None).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(res.headers()[header::WWW_AUTHENTICATE], "Bearer");
+    assert!(!body.contains("request_id"));
+
+    // внутренние подробности не попадают в ответ
+    let (res, body) = call("/internal", "application/json", None).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "internal_error");
+    assert_eq!(body["message"], "Error, try again later");
+}
+
+#[tokio::test]
+async fn error_html_test() {
+    let (res, body) = call("/conflict", "text/html,application/xhtml+xml,application/json;q=0.9", Some("<req>")).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert!(res.headers()[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/html"));
+    assert!(body.contains("<h1>This nickname is already taken</h1>"));
+    assert!(body.contains("&lt;req&gt;"));
+
+    let (res, body) = call("/ok", "text/html", None).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(body, "ok");
+}
+
+#[tokio::test]
+async fn panic_test() {
+    let (res, body) = call("/panic", "application/json", Some("req-2")).await;
+    assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+    let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(body["error"], "internal_error");
+    assert_eq!(body["request_id"], "req-2");
+    assert!(!body.to_string().contains("handler bug"));
+}
@@ -0,0 +1,115 @@
+use rp::models::*;
+use chrono::{DateTime, Duration, Utc};
+use jsonwebtoken::decode_header;
+use std::{env, fs, sync::{Arc, Mutex}};
+
+/// Часы, которые двигает сам тест
+struct TestClock(Mutex<DateTime<Utc>>);
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Ротация ключей - перезапись файла по пути из `jwt.keyring`
+fn write_keyring(name: &str, body: &str) -> String {
+    let path = env::temp_dir().join(name);
+    fs::write(&path, body).expect("Не удалось записать keyring");
+    path.to_string_lossy().to_string()
+}
+
+#[tokio::test]
+async fn key_rotation_test() {
+    let mut config = Config::load().unwrap();
+    config.jwt.keyring = write_keyring("rp_keyring_rotation.json", r#"{
+        "active": "default",
+        "keys": [{"kid": "default", "private": "ed25519_private.pem", "public": "ed25519_public.pem"}]
+    }"#);
+    let keys = Arc::new(KeyStore::load(&config.jwt, Arc::new(SystemClock)).await.unwrap());
+    let old_token = Jwt::create_acc_token("1", &UserGrants::default(), Arc::clone(&keys)).await.unwrap();
+    assert_eq!(decode_header(&old_token).unwrap().kid.as_deref(), Some("default"));
+
+    write_keyring("rp_keyring_rotation.json", &format!(r#"{{
+        "active": "rotated",
+        "grace_days": 30,
+        "keys": [
+            {{"kid": "rotated", "private": "tests/keys/rotated_private.pem", "public": "tests/keys/rotated_public.pem"}},
+            {{"kid": "default", "public": "ed25519_public.pem", "retired_at": "{}"}}
+        ]
+    }}"#, (Utc::now() - Duration::days(1)).to_rfc3339()));
+    keys.reload().await.unwrap();
+    let new_token = Jwt::create_acc_token("1", &UserGrants::default(), Arc::clone(&keys)).await.unwrap();
+    assert_eq!(decode_header(&new_token).unwrap().kid.as_deref(), Some("rotated"));
+    assert!(Jwt::verify_acc_token(&old_token, Arc::clone(&keys)).await.is_ok());
+    assert!(Jwt::verify_acc_token(&new_token, Arc::clone(&keys)).await.is_ok());
+    assert_eq!(keys.current().jwks(&*keys.clock).keys.len(), 2);
+
+    write_keyring("rp_keyring_rotation.json", &format!(r#"{{
+        "active": "rotated",
+        "grace_days": 30,
+        "keys": [
+            {{"kid": "rotated", "private": "tests/keys/rotated_private.pem", "public": "tests/keys/rotated_public.pem"}},
+            {{"kid": "default", "public": "ed25519_public.pem", "retired_at": "{}"}}
+        ]
+    }}"#, (Utc::now() - Duration::days(31)).to_rfc3339()));
+    keys.reload().await.unwrap();
+    assert!(Jwt::verify_acc_token(&old_token, Arc::clone(&keys)).await.is_err());
+    assert!(Jwt::verify_acc_token(&new_token, Arc::clone(&keys)).await.is_ok());
+    assert_eq!(keys.current().jwks(&*keys.clock).keys.len(), 1);
+
+    write_keyring("rp_keyring_rotation.json", r#"{"active": "missing", "keys": []}"#);
+    assert!(keys.reload().await.is_err());
+    assert_eq!(keys.current().active, "rotated");
+}
+
+#[tokio::test]
+async fn retired_key_grace_clock_test() {
+    let mut config = Config::load().unwrap();
+    let now = Utc::now();
+    let retired_at = now - Duration::days(29);
+    config.jwt.keyring = write_keyring("rp_keyring_grace.json", &format!(r#"{{
+        "active": "rotated",
+        "grace_days": 30,
+        "keys": [
+            {{"kid": "rotated", "private": "tests/keys/rotated_private.pem", "public": "tests/keys/rotated_public.pem"}},
+            {{"kid": "default", "public": "ed25519_public.pem", "retired_at": "{}"}}
+        ]
+    }}"#, retired_at.to_rfc3339()));
+    let clock = Arc::new(TestClock(Mutex::new(now)));
+    let keys = Arc::new(KeyStore::load(&config.jwt, Arc::clone(&clock) as Arc<dyn Clock>).await.unwrap());
+
+    // токен, подписанный ключом "default", пока он ещё был активным
+    let mut old_settings = config.jwt.clone();
+    old_settings.keyring = write_keyring("rp_keyring_grace_old.json", r#"{
+        "active": "default",
+        "keys": [{"kid": "default", "private": "ed25519_private.pem", "public": "ed25519_public.pem"}]
+    }"#);
+    let old_keys = Arc::new(KeyStore::load(&old_settings, Arc::clone(&clock) as Arc<dyn Clock>).await.unwrap());
+    let old_token = Jwt::create_acc_token("1", &UserGrants::default(), old_keys).await.unwrap();
+
+    // grace период считается по часам KeyStore, а не по системному времени
+    assert!(Jwt::verify_acc_token(&old_token, Arc::clone(&keys)).await.is_ok());
+    assert_eq!(keys.current().jwks(&*keys.clock).keys.len(), 2);
+
+    *clock.0.lock().unwrap() = now + Duration::days(2);
+    assert!(Jwt::verify_acc_token(&old_token, Arc::clone(&keys)).await.is_err());
+    assert_eq!(keys.current().jwks(&*keys.clock).keys.len(), 1);
+}
+
+#[test]
+fn bearer_token_test() {
+    use axum::http::{header, HeaderMap, HeaderValue};
+
+    let mut headers = HeaderMap::new();
+    assert_eq!(Jwt::get_bearer_token(&headers), "");
+
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer abc.def.ghi"));
+    assert_eq!(Jwt::get_bearer_token(&headers), "abc.def.ghi");
+
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_static("bearer  abc.def.ghi "));
+    assert_eq!(Jwt::get_bearer_token(&headers), "abc.def.ghi");
+
+    headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic dXNlcjpwYXNz"));
+    assert_eq!(Jwt::get_bearer_token(&headers), "");
+}
@@ -0,0 +1,750 @@
+use rp::{app::build_router, models::*};
+use rp::services::jwt_service::REVOKED_ACCESS;
+use axum::{body::{to_bytes, Body}, http::{header, Method, Request, StatusCode}, Router};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use async_trait::async_trait;
+use std::sync::{atomic::{AtomicBool, AtomicUsize, Ordering}, Arc, Mutex};
+use tower::ServiceExt;
+
+/// Часы, которые двигает сам тест
+struct TestClock(Mutex<DateTime<Utc>>);
+
+impl Clock for TestClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl TestClock {
+    fn advance(&self, secs: i64) {
+        *self.0.lock().unwrap() += Duration::seconds(secs);
+    }
+
+    fn advance_ms(&self, millis: i64) {
+        *self.0.lock().unwrap() += Duration::milliseconds(millis);
+    }
+}
+
+/// Без .env, Postgres и Redis: всё хранится в памяти
+async fn app() -> (Router, AppState, Arc<TestClock>) {
+    let mut config = Config::default();
+    config.argon.memory_kib = 1024;
+    config.argon.iterations = 1;
+    config.jwt.refresh_token_key = "0f".repeat(32);
+
+    let clock = Arc::new(TestClock(Mutex::new(Utc::now())));
+    let state = AppState::in_memory(config, Arc::clone(&clock) as Arc<dyn Clock>).await.unwrap();
+    (build_router(state.clone()), state, clock)
+}
+
+async fn send(app: &Router, method: Method, uri: &str, headers: &[(header::HeaderName, String)], body: Option<Value>) -> (StatusCode, Value) {
+    let mut req = Request::builder().method(method).uri(uri);
+    for (name, value) in headers {
+        req = req.header(name, value);
+    }
+    let req = match body {
+        Some(body) => req.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+        None => req.body(Body::empty()),
+    };
+
+    let res = app.clone().oneshot(req.unwrap()).await.unwrap();
+    let status = res.status();
+    let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+}
+
+fn bearer(token: &Value) -> Vec<(header::HeaderName, String)> {
+    vec![(header::AUTHORIZATION, format!("Bearer {}", token.as_str().unwrap()))]
+}
+
+async fn refresh(app: &Router, refresh_token: &Value) -> (StatusCode, Value) {
+    send(app, Method::POST, "/api/v1/auth/refresh", &[], Some(json!({"refresh_token": refresh_token}))).await
+}
+
+#[tokio::test]
+async fn memory_auth_flow_test() {
+    let (app, state, clock) = app().await;
+    let user = json!({"nickname": "memory-user", "name": "Memory", "password": "12345678"});
+
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(user.clone())).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, error) = send(&app, Method::POST, "/api/v1/auth/register", &[], Some(user)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(error["error"], "nickname_taken");
+
+    let (status, error) = send(&app, Method::POST, "/api/v1/auth/login", &[], Some(json!({"nickname": "memory-user", "password": "wrong-password"}))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "invalid_credentials");
+    let (status, logged_in) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": "memory-user", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, sessions) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&logged_in["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sessions.as_array().map(Vec::len), Some(2));
+
+    // ротация: параллельный запрос со старым токеном в пределах grace получает ту же пару
+    let (status, rotated) = refresh(&app, &logged_in["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_ne!(rotated["refresh_token"], logged_in["refresh_token"]);
+    let (status, repeated) = refresh(&app, &logged_in["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(repeated["refresh_token"], rotated["refresh_token"]);
+
+    // после grace повторное использование отзывает всю семью
+    clock.advance(state.config.jwt.refresh_grace as i64 + 1);
+    let (status, error) = refresh(&app, &logged_in["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "invalid_refresh_token");
+    let (status, _) = refresh(&app, &rotated["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, sessions) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&registered["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(sessions.as_array().map(Vec::len), Some(1));
+
+    // выход по cookie: access токен отзывается, refresh токен удаляется
+    let cookie = format!("AccessToken={}; RefreshToken={}",
+        registered["access_token"].as_str().unwrap(), registered["refresh_token"].as_str().unwrap());
+    let (status, _) = send(&app, Method::GET, "/logout", &[(header::COOKIE, cookie)], None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&registered["access_token"]), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = refresh(&app, &registered["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn memory_cache_ttl_test() {
+    let clock = Arc::new(TestClock(Mutex::new(Utc::now())));
+    let cache = MemoryCache::new(Arc::clone(&clock) as Arc<dyn Clock>);
+
+    cache.set_str("key", "value", 10).await.unwrap();
+    assert_eq!(cache.incr("counter").await.unwrap(), 1);
+    assert_eq!(cache.incr("counter").await.unwrap(), 2);
+    assert_eq!(cache.mget_str(&["key".to_owned(), "missing".to_owned()]).await.unwrap(), vec![Some("value".to_owned()), None]);
+
+    clock.advance(10);
+    assert!(matches!(cache.get_str("key").await, Err(CustomRedisError::NoneError)));
+    assert_eq!(cache.get_str("counter").await.unwrap(), "2");
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Entry {
+    id: i64,
+    tags: Vec<String>,
+}
+
+#[tokio::test]
+async fn typed_cache_test() {
+    const ENTRIES: CacheNamespace = CacheNamespace::new("entries", 2);
+    let cache: Arc<dyn Cache> = Arc::new(MemoryCache::new(Arc::new(SystemClock)));
+    assert_eq!(ENTRIES.key(7), "entries:v2:7");
+
+    let entry = Entry { id: 7, tags: vec!["a".to_owned()] };
+    cache.set(&ENTRIES.key(7), &entry, 60).await.unwrap();
+    assert_eq!(cache.get::<Entry>(&ENTRIES.key(7)).await.unwrap(), Some(entry));
+    assert_eq!(cache.get::<Entry>(&ENTRIES.key(8)).await.unwrap(), None);
+
+    let items: Vec<(String, Entry)> = (1..=3).map(|id| (ENTRIES.key(id), Entry { id, tags: Vec::new() })).collect();
+    cache.mset(&items, 60).await.unwrap();
+    cache.set_str(&ENTRIES.key(4), "not json", 60).await.unwrap();
+
+    let keys: Vec<String> = (1..=5).map(|id| ENTRIES.key(id)).collect();
+    let found = cache.mget::<Entry>(&keys).await.unwrap();
+    assert_eq!(found.iter().map(|entry| entry.as_ref().map(|entry| entry.id)).collect::<Vec<_>>(), vec![Some(1), Some(2), Some(3), None, None]);
+    assert!(matches!(cache.get::<Entry>(&ENTRIES.key(4)).await, Err(CustomRedisError::ValueError(_))));
+}
+
+#[tokio::test]
+async fn single_flight_test() {
+    let flights = Arc::new(SingleFlight::new());
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let runs = (0..10).map(|_| {
+        let (flights, calls) = (Arc::clone(&flights), Arc::clone(&calls));
+        async move {
+            flights.run("key", async move {
+                calls.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                Ok(42)
+            }).await
+        }
+    });
+    let results = futures::future::join_all(runs).await;
+    assert!(results.iter().all(|res| matches!(res, Ok(42))));
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+    // после завершения ключ свободен, следующий промах снова идёт в хранилище
+    let res = flights.run("key", async { Err::<i32, _>(DataBaseError::NotFound) }).await;
+    assert!(matches!(res, Err(DataBaseError::NotFound)));
+}
+
+#[tokio::test]
+async fn negative_cache_test() {
+    let (_, state, clock) = app().await;
+    let get = || DataBase::get_user("ghost", Arc::clone(&state.users), Arc::clone(&state.cache), Arc::clone(&state.flights), &state.config);
+
+    assert!(matches!(get().await, Err(DataBaseError::NotFound)));
+
+    // пользователь появился в обход кеша: до истечения negative_ttl он всё ещё не найден
+    state.users.create_user("ghost", "Ghost", "hash").await.unwrap();
+    assert!(matches!(get().await, Err(DataBaseError::NotFound)));
+
+    let redis = &state.config.redis;
+    clock.advance((redis.negative_ttl * (100 + redis.ttl_jitter) / 100 + 1) as i64);
+    assert_eq!(get().await.unwrap().nickname, "ghost");
+}
+
+#[tokio::test]
+async fn layered_cache_invalidation_test() {
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut config = Config::default().redis;
+    config.l1_ttl = 60;
+    // два экземпляра приложения с общим кешем и общей шиной событий
+    let remote: Arc<dyn Cache> = Arc::new(MemoryCache::new(Arc::clone(&clock)));
+    let bus: Arc<dyn EventBus> = Arc::new(MemoryEventBus::new());
+    let first = LayeredCache::wrap(Arc::clone(&remote), &*bus, &config, Arc::clone(&clock));
+    let second = LayeredCache::wrap(Arc::clone(&remote), &*bus, &config, Arc::clone(&clock));
+
+    let event = CacheEvent::UserChanged { id: 7, old_nickname: Some("old".to_owned()), new_nickname: "new".to_owned() };
+    let keys = event.keys();
+    assert!(keys.contains(&"user_nick:v1:old".to_owned()) && keys.contains(&"user_nick:v1:new".to_owned()));
+
+    first.set_str("user_nick:v1:old", "profile", 60).await.unwrap();
+    assert_eq!(second.get_str("user_nick:v1:old").await.unwrap(), "profile");
+
+    // первый экземпляр переименовал пользователя: во втором копия живёт в L1 до события
+    first.del("user_nick:v1:old").await.unwrap();
+    assert_eq!(second.get_str("user_nick:v1:old").await.unwrap(), "profile");
+
+    bus.notify(event).await;
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    assert!(matches!(second.get_str("user_nick:v1:old").await, Err(CustomRedisError::NoneError)));
+}
+
+/// Кеш, который можно «выключить», как упавший Redis
+struct FlakyCache {
+    down: AtomicBool,
+    inner: MemoryCache,
+}
+
+impl FlakyCache {
+    fn check(&self) -> Result<(), CustomRedisError> {
+        if self.down.load(Ordering::SeqCst) { Err(CustomRedisError::ConnectError) } else { Ok(()) }
+    }
+}
+
+#[async_trait]
+impl Cache for FlakyCache {
+    async fn get_str(&self, key: &str) -> Result<String, CustomRedisError> {
+        self.check()?;
+        self.inner.get_str(key).await
+    }
+
+    async fn set_str(&self, key: &str, value: &str, ttl: u64) -> Result<(), CustomRedisError> {
+        self.check()?;
+        self.inner.set_str(key, value, ttl).await
+    }
+
+    async fn mget_str(&self, keys: &[String]) -> Result<Vec<Option<String>>, CustomRedisError> {
+        self.check()?;
+        self.inner.mget_str(keys).await
+    }
+
+    async fn mset_str(&self, items: &[(String, String)], ttl: u64) -> Result<(), CustomRedisError> {
+        self.check()?;
+        self.inner.mset_str(items, ttl).await
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CustomRedisError> {
+        self.check()?;
+        self.inner.del(key).await
+    }
+
+    async fn incr(&self, key: &str) -> Result<i64, CustomRedisError> {
+        self.check()?;
+        self.inner.incr(key).await
+    }
+}
+
+fn flaky_cache(down: bool) -> Arc<FlakyCache> {
+    Arc::new(FlakyCache { down: AtomicBool::new(down), inner: MemoryCache::new(Arc::new(SystemClock)) })
+}
+
+#[tokio::test]
+async fn circuit_breaker_test() {
+    let flaky = flaky_cache(true);
+    let breaker = Arc::new(CircuitBreaker::new(3));
+    let cache = BreakerCache::wrap(Arc::clone(&flaky) as Arc<dyn Cache>, Arc::clone(&breaker), std::time::Duration::from_millis(50));
+
+    // отсутствующий ключ не считается сбоем
+    flaky.down.store(false, Ordering::SeqCst);
+    for _ in 0..5 {
+        assert!(matches!(cache.get_str("missing").await, Err(CustomRedisError::NoneError)));
+    }
+    assert!(!breaker.is_open());
+
+    flaky.down.store(true, Ordering::SeqCst);
+    for _ in 0..3 {
+        assert!(matches!(cache.get_str("key").await, Err(CustomRedisError::ConnectError)));
+    }
+    assert!(breaker.is_open());
+    assert!(matches!(cache.set_str("key", "value", 60).await, Err(CustomRedisError::Unavailable)));
+    assert!(breaker.metrics().contains("rp_cache_circuit_open 1\n"));
+    assert!(breaker.metrics().contains("rp_cache_bypassed_total 1\n"));
+
+    // фоновая проверка закрывает breaker, когда кеш снова отвечает
+    flaky.down.store(false, Ordering::SeqCst);
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+    assert!(!breaker.is_open());
+    cache.set_str("key", "value", 60).await.unwrap();
+    assert_eq!(cache.get_str("key").await.unwrap(), "value");
+}
+
+#[tokio::test]
+async fn cache_down_auth_flow_test() {
+    let (_, state, _) = app().await;
+    let breaker = Arc::new(CircuitBreaker::new(state.config.redis.failure_threshold));
+    let cache = BreakerCache::wrap(flaky_cache(true), Arc::clone(&breaker), std::time::Duration::from_secs(60));
+    let state = AppState { cache, breaker, ..state };
+    let app = build_router(state.clone());
+
+    let user = json!({"nickname": "no-redis", "name": "No Redis", "password": "12345678"});
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(user)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, logged_in) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": "no-redis", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // отзыв не проверить: по умолчанию access токен не принимается
+    let (status, _) = send(&app, Method::PATCH, "/api/v1/users/me", &bearer(&logged_in["access_token"]), Some(json!({"name": "Still works"}))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // с jwt.revocation_fail_open принимается
+    let mut config = (*state.config).clone();
+    config.jwt.revocation_fail_open = true;
+    let keys = Arc::new(KeyStore::load(&config.jwt, Arc::clone(&state.clock)).await.unwrap());
+    let state = AppState { keys, config: Arc::new(config), ..state };
+    let app = build_router(state.clone());
+    let (status, _) = send(&app, Method::PATCH, "/api/v1/users/me", &bearer(&logged_in["access_token"]), Some(json!({"name": "Still works"}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = refresh(&app, &registered["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, page) = send(&app, Method::GET, "/all", &[], None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(page["items"][0]["name"], "Still works");
+
+    assert!(state.breaker.is_open());
+    let (status, health) = send(&app, Method::GET, "/health", &[], None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["status"], "degraded");
+    assert_eq!(health["cache"], "bypassed");
+}
+
+#[tokio::test]
+async fn password_rehash_test() {
+    let (app, state, _) = app().await;
+    for nickname in ["rehash-user", "rehash-admin"] {
+        let (status, _) = send(&app, Method::POST, "/api/v1/auth/register", &[], Some(json!({"nickname": nickname, "name": "Rehash", "password": "12345678"}))).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let admin = state.users.get_user_credentials("rehash-admin").await.unwrap();
+    state.users.grant_role(&admin.id.to_string(), "Admin").await.unwrap();
+
+    // подняли стоимость хеширования: старые хеши теперь слабее политики
+    let mut config = (*state.config).clone();
+    config.argon.iterations = 2;
+    assert!(Argon::needs_rehash(admin.password.as_str(), &config.argon));
+    let state = AppState { config: Arc::new(config), ..state };
+    let app = build_router(state.clone());
+
+    let login = |nickname: &'static str| {
+        let app = app.clone();
+        async move { send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": nickname, "password": "12345678"}))).await }
+    };
+    let (status, tokens) = login("rehash-admin").await;
+    assert_eq!(status, StatusCode::OK);
+    let params = Argon::hash_params(state.users.get_user_credentials("rehash-admin").await.unwrap().password.as_str()).unwrap();
+    assert_eq!((params.algorithm.as_str(), params.iterations, params.memory_kib), ("argon2id", 2, 1024));
+
+    let (status, report) = send(&app, Method::GET, "/api/v1/admin/password-hashes", &bearer(&tokens["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!((report["total"].as_i64(), report["legacy"].as_i64()), (Some(2), Some(1)));
+
+    let (status, _) = login("rehash-user").await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, report) = send(&app, Method::GET, "/api/v1/admin/password-hashes", &bearer(&tokens["access_token"]), None).await;
+    assert_eq!(report["legacy"].as_i64(), Some(0));
+
+    // с новым хешем вход работает, а без users:read отчёт недоступен
+    let (status, user_tokens) = login("rehash-user").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, Method::GET, "/api/v1/admin/password-hashes", &bearer(&user_tokens["access_token"]), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn hash_pool_backpressure_test() {
+    let (app, state, _) = app().await;
+    let user = json!({"nickname": "busy-user", "password": "12345678"});
+    let (status, _) = send(&app, Method::POST, "/api/v1/auth/register", &[], Some(json!({"nickname": "busy-user", "name": "Busy", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // один worker без очереди: пока он занят, любое хеширование отклоняется сразу
+    let mut argon = state.config.argon.clone();
+    argon.workers = 1;
+    argon.queue = 0;
+    argon.retry_after = 3;
+    let state = AppState { hasher: Arc::new(HashPool::new(&argon)), ..state };
+    let app = build_router(state.clone());
+
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let hasher = Arc::clone(&state.hasher);
+    let job = tokio::spawn(async move {
+        hasher.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().ok();
+        }).await
+    });
+    tokio::task::spawn_blocking(move || started_rx.recv().unwrap()).await.unwrap();
+
+    let req = Request::builder().method(Method::POST).uri("/api/v1/auth/login?mode=token")
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(user.to_string())).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "3");
+    let body: Value = serde_json::from_slice(&to_bytes(res.into_body(), usize::MAX).await.unwrap()).unwrap();
+    assert_eq!(body["error"], "overloaded");
+
+    let res = app.clone().oneshot(Request::get("/metrics").body(Body::empty()).unwrap()).await.unwrap();
+    let metrics = String::from_utf8(to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap();
+    assert!(metrics.contains("rp_hash_rejected_total 1\n"));
+    assert!(metrics.contains("rp_hash_queued 0\n"));
+
+    release_tx.send(()).unwrap();
+    job.await.unwrap().unwrap();
+    let (status, _) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(user)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn legacy_refresh_token_hash_test() {
+    let (app, state, _) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "legacy-user", "name": "Legacy", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // новые токены хранятся как HMAC digest и ищутся по нему
+    let token = registered["refresh_token"].as_str().unwrap();
+    let digest = Jwt::token_digest(token, &state.keys.refresh_key);
+    assert_eq!(digest.len(), 64);
+    let (user_id, _) = state.tokens.find_token_by_digest(&digest).await.unwrap();
+
+    // токен, сохранённый до перехода на HMAC, с Argon2 хешем
+    let legacy = Jwt::create_ref_token(&user_id.to_string(), "User", Arc::clone(&state.keys)).await.unwrap();
+    let claims = Jwt::verify_ref_token(&legacy, Arc::clone(&state.keys), Arc::clone(&state.tokens), Arc::clone(&state.hasher), false).await.unwrap();
+    let now = Utc::now();
+    state.tokens.save_ref_token(&RefreshTokenRecord {
+        jti: claims.jti,
+        user_id,
+        token_hash: Argon::hash_str(&legacy, &state.config.argon, &state.hasher).await.unwrap(),
+        family_id: "legacy-family".to_owned(),
+        created_at: now,
+        expires_at: now + Duration::days(30),
+        last_used_at: now,
+        rotated_at: None,
+        device: DeviceInfo::default(),
+    }).await.unwrap();
+
+    let (status, rotated) = refresh(&app, &json!(legacy)).await;
+    assert_eq!(status, StatusCode::OK);
+    let digest = Jwt::token_digest(rotated["refresh_token"].as_str().unwrap(), &state.keys.refresh_key);
+    assert!(state.tokens.find_token_by_digest(&digest).await.is_ok());
+
+    // с другим ключом digest другой, и токен не находится
+    let other = RefreshTokenKey::from_hex(&"01".repeat(32)).unwrap();
+    assert!(state.tokens.find_token_by_digest(&Jwt::token_digest(token, &other)).await.is_err());
+}
+
+#[tokio::test]
+async fn token_type_test() {
+    let (app, _, _) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "typ-user", "name": "Typ", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // refresh токен живёт дольше и не отзывается watermark'ом, как access токен он не принимается
+    let (status, error) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&registered["refresh_token"]), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "unauthorized");
+    let (status, _) = refresh(&app, &registered["access_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, _) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&registered["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn hash_pool_cancelled_job_test() {
+    let argon = ArgonConfig { workers: 1, queue: 0, ..Default::default() };
+    let hasher = Arc::new(HashPool::new(&argon));
+
+    let (started_tx, started_rx) = std::sync::mpsc::channel();
+    let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let pool = Arc::clone(&hasher);
+    let request = tokio::spawn(async move {
+        pool.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().ok();
+            done_tx.send(()).unwrap();
+        }).await
+    });
+    tokio::task::spawn_blocking(move || started_rx.recv().unwrap()).await.unwrap();
+
+    // клиент ушёл, но хеш ещё считается и держит worker
+    request.abort();
+    assert!(request.await.unwrap_err().is_cancelled());
+    assert!(matches!(hasher.run(|| ()).await, Err(ArgonError::Busy { .. })));
+
+    release_tx.send(()).unwrap();
+    tokio::task::spawn_blocking(move || done_rx.recv().unwrap()).await.unwrap();
+    // permit освобождается сразу после job, но в другом потоке
+    for _ in 0..50 {
+        if hasher.run(|| ()).await.is_ok() {
+            return
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    }
+    panic!("worker is not released after the job finished");
+}
+
+/// Хранилище refresh токенов, в котором можно сломать запись
+struct BrokenWrites {
+    broken: AtomicBool,
+    inner: MemoryRefreshTokenRepository,
+}
+
+impl BrokenWrites {
+    fn check(&self) -> Result<(), DataBaseError> {
+        if self.broken.load(Ordering::SeqCst) { Err(DataBaseError::SaveError) } else { Ok(()) }
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepository for BrokenWrites {
+    async fn save_ref_token(&self, token: &RefreshTokenRecord) -> Result<(), DataBaseError> {
+        self.check()?;
+        self.inner.save_ref_token(token).await
+    }
+
+    async fn get_token_hash(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        self.inner.get_token_hash(user_id, jti).await
+    }
+
+    async fn find_token_by_digest(&self, digest: &str) -> Result<(i64, String), DataBaseError> {
+        self.inner.find_token_by_digest(digest).await
+    }
+
+    async fn rotate_ref_token(&self, user_id: &str, jti: &str) -> Result<String, DataBaseError> {
+        self.inner.rotate_ref_token(user_id, jti).await
+    }
+
+    async fn revoke_ref_family(&self, user_id: &str, family_id: &str) -> Result<(), DataBaseError> {
+        self.check()?;
+        self.inner.revoke_ref_family(user_id, family_id).await
+    }
+
+    async fn del_ref_token(&self, user_id: &str, jti: &str) -> Result<(), DataBaseError> {
+        self.inner.del_ref_token(user_id, jti).await
+    }
+
+    async fn del_all_ref_tokens(&self, user_id: &str) -> Result<(), DataBaseError> {
+        self.inner.del_all_ref_tokens(user_id).await
+    }
+
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<Session>, DataBaseError> {
+        self.inner.get_sessions(user_id).await
+    }
+
+    async fn purge_expired_tokens(&self) -> Result<u64, DataBaseError> {
+        self.check()?;
+        self.inner.purge_expired_tokens().await
+    }
+}
+
+#[tokio::test]
+async fn refresh_write_errors_test() {
+    let (_, state, clock) = app().await;
+    let tokens = Arc::new(BrokenWrites { broken: AtomicBool::new(false), inner: MemoryRefreshTokenRepository::new(Arc::clone(&clock) as Arc<dyn Clock>) });
+    let state = AppState { tokens: Arc::clone(&tokens) as Arc<dyn RefreshTokenRepository>, ..state };
+    let app = build_router(state.clone());
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "broken-user", "name": "Broken", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    // новый токен не сохранился: клиент получает ошибку, а не пустой или неизвестный БД токен
+    tokens.broken.store(true, Ordering::SeqCst);
+    let (status, error) = refresh(&app, &registered["refresh_token"]).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(error["error"], "invalid_refresh_token");
+
+    // повторное использование после grace: семью отозвать не удалось, это тоже ошибка, а не RefreshReuse
+    clock.advance(state.config.jwt.refresh_grace as i64 + 1);
+    let err = Jwt::refresh_pair(registered["refresh_token"].as_str().unwrap(), &DeviceInfo::default(), &state).await.unwrap_err();
+    assert!(matches!(err, JwtError::Refresh(_)));
+
+    tokens.broken.store(false, Ordering::SeqCst);
+    let err = Jwt::refresh_pair(registered["refresh_token"].as_str().unwrap(), &DeviceInfo::default(), &state).await.unwrap_err();
+    assert!(matches!(err, JwtError::RefreshReuse));
+    let (_, sessions) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&registered["access_token"]), None).await;
+    assert_eq!(sessions.as_array().map(Vec::len), Some(0));
+}
+
+#[tokio::test]
+async fn purge_expired_tokens_test() {
+    let (app, state, clock) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "purge-user", "name": "Purge", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, _) = refresh(&app, &registered["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // ротированный и текущий токены живут до expires_at
+    assert_eq!(state.tokens.purge_expired_tokens().await.unwrap(), 0);
+    clock.advance(Duration::days(state.config.jwt.refresh_ttl_days).num_seconds() + 1);
+    assert_eq!(state.tokens.purge_expired_tokens().await.unwrap(), 2);
+    assert_eq!(state.tokens.purge_expired_tokens().await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn refresh_token_key_test() {
+    // без ключа или с неверным ключом сервер не запускается
+    for key in ["", "abc", &"0f".repeat(16)] {
+        let mut config = Config::default();
+        config.jwt.refresh_token_key = key.to_owned();
+        let res = AppState::in_memory(config, Arc::new(SystemClock)).await;
+        assert!(matches!(res, Err(StartupError::Jwt(JwtError::RefreshKey(_)))));
+    }
+    assert!(RefreshTokenKey::from_hex(&"0f".repeat(32)).is_ok());
+}
+
+#[tokio::test]
+async fn revoke_all_same_instant_test() {
+    let (app, state, clock) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "same-second", "name": "Same", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, other) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": "same-second", "password": "12345678"}))).await;
+
+    // оба токена выданы в ту же секунду, что и отзыв, но на миллисекунду раньше
+    clock.advance_ms(1);
+    let (status, _) = send(&app, Method::POST, "/api/v1/sessions/revoke-all", &bearer(&registered["access_token"]), None).await;
+    assert!(status.is_success());
+    let (status, _) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&other["access_token"]), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // вход в тот же момент, что и отзыв: новая пара действует
+    let (status, fresh) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": "same-second", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&fresh["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, refreshed) = refresh(&app, &fresh["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&app, Method::GET, "/api/v1/sessions", &bearer(&refreshed["access_token"]), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // denylist живёт по часам приложения, а не по системному времени
+    clock.advance(1);
+    let (_, fresh) = send(&app, Method::POST, "/api/v1/auth/login?mode=token", &[], Some(json!({"nickname": "same-second", "password": "12345678"}))).await;
+    let claims = Jwt::verify_acc_token(fresh["access_token"].as_str().unwrap(), Arc::clone(&state.keys)).await.unwrap();
+    Jwt::revoke_acc_token(&claims, Arc::clone(&state.keys), Arc::clone(&state.cache)).await.unwrap();
+    assert!(Jwt::is_acc_token_revoked(&claims, Arc::clone(&state.keys), Arc::clone(&state.cache)).await);
+    clock.advance(state.config.jwt.access_ttl + 1);
+    assert!(state.cache.get_str(&REVOKED_ACCESS.key(&claims.jti)).await.is_err());
+}
+
+#[tokio::test]
+async fn logout_without_refresh_token_test() {
+    let (app, _, _) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "logout-user", "name": "Logout", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, rotated) = refresh(&app, &registered["refresh_token"]).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // refresh cookie неверный или его нет: cookie всё равно очищаются
+    let access = rotated["access_token"].as_str().unwrap();
+    for cookie in [format!("AccessToken={access}; RefreshToken=expired"), format!("AccessToken={access}")] {
+        let req = Request::get("/logout").header(header::COOKIE, cookie).body(Body::empty()).unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_cookies_cleared(&res);
+    }
+}
+
+/// Очищающие cookie должны совпадать по пути с выданными при входе, иначе браузер их не заменит
+fn assert_cookies_cleared(res: &axum::response::Response) {
+    let cleared: Vec<_> = res.headers().get_all(header::SET_COOKIE).iter().map(|val| val.to_str().unwrap().to_owned()).collect();
+    for name in ["AccessToken", "RefreshToken"] {
+        let cookie = cleared.iter().find(|val| val.starts_with(&format!("{name}=;"))).unwrap();
+        assert!(cookie.split("; ").any(|attr| attr == "Path=/"), "{cookie}");
+        assert!(cookie.split("; ").any(|attr| attr == "SameSite=Strict"), "{cookie}");
+    }
+}
+
+#[tokio::test]
+async fn revoke_all_clears_cookies_test() {
+    let (app, _, _) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "revoke-all-cookies", "name": "Cookies", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let req = Request::post("/api/v1/sessions/revoke-all")
+        .header(header::AUTHORIZATION, format!("Bearer {}", registered["access_token"].as_str().unwrap()))
+        .body(Body::empty()).unwrap();
+    let res = app.clone().oneshot(req).await.unwrap();
+    assert!(res.status().is_success());
+    assert_cookies_cleared(&res);
+}
+
+#[tokio::test]
+async fn register_rollback_test() {
+    let (_, state, clock) = app().await;
+    let tokens = Arc::new(BrokenWrites { broken: AtomicBool::new(true), inner: MemoryRefreshTokenRepository::new(Arc::clone(&clock) as Arc<dyn Clock>) });
+    let state = AppState { tokens: Arc::clone(&tokens) as Arc<dyn RefreshTokenRepository>, ..state };
+    let app = build_router(state.clone());
+    let user = json!({"nickname": "rollback-user", "name": "Rollback", "password": "12345678"});
+
+    // refresh токен не сохранился: пользователь не остаётся без токенов и nickname свободен
+    let (status, _) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(user.clone())).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert!(matches!(state.users.get_user_credentials("rollback-user").await, Err(DataBaseError::NotFound)));
+    let (status, _) = send(&app, Method::GET, "/profile/rollback-user", &[], None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    tokens.broken.store(false, Ordering::SeqCst);
+    let (status, _) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(user)).await;
+    assert_eq!(status, StatusCode::CREATED);
+}
+
+#[tokio::test]
+async fn user_roles_read_permission_test() {
+    let (app, state, _) = app().await;
+    let (status, registered) = send(&app, Method::POST, "/api/v1/auth/register?mode=token", &[], Some(json!({"nickname": "roles-reader", "name": "Reader", "password": "12345678"}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let claims = Jwt::verify_acc_token(registered["access_token"].as_str().unwrap(), Arc::clone(&state.keys)).await.unwrap();
+    let uri = format!("/api/v1/admin/users/{}/roles", claims.sub);
+
+    // без прав роли не видны
+    let (status, _) = send(&app, Method::GET, &uri, &bearer(&registered["access_token"]), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // users:read достаточно для чтения ролей, но не для выдачи и отзыва
+    let grants = UserGrants { roles: vec!["User".to_owned()], scopes: vec!["users:read".to_owned()] };
+    let reader = json!(Jwt::create_acc_token(&claims.sub, &grants, Arc::clone(&state.keys)).await.unwrap());
+    let (status, roles) = send(&app, Method::GET, &uri, &bearer(&reader), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(roles.to_string().contains("User"));
+    let (status, _) = send(&app, Method::POST, &uri, &bearer(&reader), Some(json!({"role": "Admin"}))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _) = send(&app, Method::DELETE, &format!("{uri}/User"), &bearer(&reader), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
@@ -0,0 +1,51 @@
+use rp::models::*;
+
+#[test]
+fn register_form_valid_test() {
+    let form = RegisterForm {
+        nickname: "test-user_1".to_owned(),
+        name: "Test User".to_owned(),
+        password: "12345678".to_owned(),
+    };
+    assert!(form.validate().is_ok())
+}
+
+#[test]
+fn register_form_invalid_test() {
+    let form = RegisterForm {
+        nickname: "a'; --".to_owned(),
+        name: "   ".to_owned(),
+        password: "123".to_owned(),
+    };
+    let errors = form.validate().unwrap_err();
+    let fields: Vec<&str> = errors.iter().map(|e| e.field).collect();
+    assert_eq!(fields, vec!["nickname", "name", "password"]);
+}
+
+#[test]
+fn login_form_empty_test() {
+    let form = LoginForm { nickname: String::new(), password: String::new() };
+    assert_eq!(form.validate().unwrap_err().len(), 2);
+}
+
+#[test]
+fn user_patch_test() {
+    assert!(UserPatch::default().validate().is_err());
+
+    let patch = UserPatch { name: Some("O'Brien".to_owned()), ..Default::default() };
+    assert!(patch.validate().is_ok());
+
+    let patch = UserPatch { nickname: Some("a b".to_owned()), password: Some("123".to_owned()), ..Default::default() };
+    let fields: Vec<&str> = patch.validate().unwrap_err().iter().map(|e| e.field).collect();
+    assert_eq!(fields, vec!["nickname", "password"]);
+}
+
+#[test]
+fn user_list_query_test() {
+    assert_eq!(UserListQuery::default().page_limit(), 20);
+    assert!(UserListQuery { limit: Some(100), ..Default::default() }.validate().is_ok());
+
+    let list = UserListQuery { limit: Some(0), q: Some(String::new()), ..Default::default() };
+    let fields: Vec<&str> = list.validate().unwrap_err().iter().map(|e| e.field).collect();
+    assert_eq!(fields, vec!["limit", "q"]);
+}